@@ -1,17 +1,18 @@
 use std::{
+    collections::BTreeMap,
     fs,
     io::Write,
     io::{self},
     net::{SocketAddr, ToSocketAddrs},
     path::PathBuf,
     sync::Arc,
-    time::Instant,
+    time::{self, Instant},
 };
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
 use clap::Parser;
 use quinn::crypto::rustls::QuicClientConfig;
-use rustls::pki_types::CertificateDer;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
 use tracing::{error, info};
 use url::Url;
 
@@ -20,6 +21,156 @@ use quinn_apps::ALPN_QUIC_HTTP;
 mod common;
 use common::{ClientStats, PeerTime};
 
+// 30fps. 1 frame every 33ms. Mirrors the server's FRAME_INTERVAL: in --unreliable mode the
+// receiver has no stream FIN to know a frame is complete, so it uses this cadence to give up
+// on a still-incomplete frame.
+const FRAME_INTERVAL: time::Duration = time::Duration::from_millis(33);
+
+/// How often the qlog task polls `Connection::stats()` for a `recovery:metrics_updated` event.
+const QLOG_METRICS_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Hand-formatted NDJSON qlog-style event stream for this connection, under `--qlog`'s
+/// directory. Mirrors the server bin's `Qlog` (a separate copy in each bin, like
+/// `DatagramHeader`); quinn doesn't expose a
+/// per-packet event hook in its public API, so this only carries a `transport:parameters_set`
+/// emitted once after the handshake and periodic `recovery:metrics_updated` samples polled
+/// from `Connection::stats()`, instead of `packet_sent`/`packet_received`.
+struct Qlog {
+    writer: fs::File,
+    begin: Instant,
+}
+
+impl Qlog {
+    fn create(dir: &PathBuf, conn_id: usize, now: Instant) -> Result<Self> {
+        fs::create_dir_all(dir).context("failed to create qlog directory")?;
+        let path = dir.join(format!("{conn_id}.qlog.jsonl"));
+        let writer = fs::File::create(&path)
+            .with_context(|| format!("failed to create qlog file {}", path.display()))?;
+        Ok(Self { writer, begin: now })
+    }
+
+    fn emit(&mut self, category: &str, fields: String) {
+        let ts_ms = self.begin.elapsed().as_secs_f64() * 1000.0;
+        if let Err(e) = writeln!(
+            self.writer,
+            r#"{{"ts_ms":{ts_ms:.3},"category":"{category}"{fields}}}"#
+        ) {
+            error!("qlog write failed, dropping event: {}", e);
+        }
+    }
+
+    fn emit_parameters_set(&mut self, remote: SocketAddr, protocol: &str) {
+        self.emit(
+            "transport:parameters_set",
+            format!(r#","remote":"{remote}","alpn":"{protocol}""#),
+        );
+    }
+
+    fn emit_metrics_updated(&mut self, stats: &quinn::ConnectionStats) {
+        self.emit(
+            "recovery:metrics_updated",
+            format!(
+                r#","cwnd":{},"rtt_ms":{:.3},"lost_packets":{},"lost_bytes":{},"sent_packets":{}"#,
+                stats.path.cwnd,
+                stats.path.rtt.as_secs_f64() * 1000.0,
+                stats.path.lost_packets,
+                stats.path.lost_bytes,
+                stats.path.sent_packets,
+            ),
+        );
+    }
+}
+
+/// Poll `conn`'s stats at `QLOG_METRICS_INTERVAL` and emit qlog events under `dir`, until the
+/// connection closes.
+async fn run_qlog(conn: quinn::Connection, dir: PathBuf) -> Result<()> {
+    let protocol = conn
+        .handshake_data()
+        .and_then(|d| d.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .map_or_else(
+            || "<none>".to_string(),
+            |d| {
+                d.protocol
+                    .map_or_else(|| "<none>".into(), |x| String::from_utf8_lossy(&x).into_owned())
+            },
+        );
+    let mut qlog = Qlog::create(&dir, conn.stable_id(), Instant::now())?;
+    qlog.emit_parameters_set(conn.remote_address(), &protocol);
+
+    let mut ticker = tokio::time::interval(QLOG_METRICS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                qlog.emit_metrics_updated(&conn.stats());
+            }
+            _ = conn.closed() => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Fixed header prepended to every unreliable frame datagram. Mirrors the server's
+/// `DatagramHeader` (decode side only); each bin keeps its own copy.
+struct DatagramHeader {
+    frame_id: u32,
+    fragment_index: u16,
+    fragment_count: u16,
+}
+
+const DATAGRAM_HEADER_LEN: usize = 4 + 2 + 2;
+
+impl DatagramHeader {
+    fn decode(buf: &[u8]) -> Option<(Self, &[u8])> {
+        if buf.len() < DATAGRAM_HEADER_LEN {
+            return None;
+        }
+        let header = DatagramHeader {
+            frame_id: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            fragment_index: u16::from_be_bytes(buf[4..6].try_into().unwrap()),
+            fragment_count: u16::from_be_bytes(buf[6..8].try_into().unwrap()),
+        };
+        Some((header, &buf[DATAGRAM_HEADER_LEN..]))
+    }
+}
+
+/// In-progress reassembly of one frame's fragments, received out of order over DATAGRAMs.
+struct FrameAssembly {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u16,
+    first_seen: Instant,
+}
+
+impl FrameAssembly {
+    fn new(fragment_count: u16, now: Instant) -> Self {
+        Self {
+            fragments: vec![None; fragment_count as usize],
+            received: 0,
+            first_seen: now,
+        }
+    }
+
+    fn insert(&mut self, fragment_index: u16, data: Vec<u8>) {
+        let slot = &mut self.fragments[fragment_index as usize];
+        if slot.is_none() {
+            self.received += 1;
+        }
+        *slot = Some(data);
+    }
+
+    fn complete(&self) -> bool {
+        self.received as usize == self.fragments.len()
+    }
+
+    fn into_frame(self) -> Vec<u8> {
+        let mut frame = Vec::new();
+        for fragment in self.fragments.into_iter().flatten() {
+            frame.extend(fragment);
+        }
+        frame
+    }
+}
+
 /// HTTP/0.9 over QUIC client
 #[derive(Parser, Debug)]
 #[clap(name = "client")]
@@ -49,6 +200,44 @@ struct Opt {
     /// Request N frames
     #[clap(long = "request-frames")]
     request_frames: usize,
+
+    /// Receive frames as best-effort QUIC DATAGRAMs instead of a reliable stream per frame.
+    /// Must match the server's --unreliable setting.
+    #[clap(long)]
+    unreliable: bool,
+
+    /// Write a qlog-style NDJSON event file for this connection to this directory
+    #[clap(long)]
+    qlog: Option<PathBuf>,
+
+    /// Client certificate chain in PEM format, for mutual TLS against a server started with
+    /// --client-ca. Requires --key; if only --key is given, a self-signed identity is derived
+    /// from it instead.
+    #[clap(long = "cert", requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// Client private key in PEM format, for mutual TLS against a server started with
+    /// --client-ca. May be given without --cert, in which case a self-signed certificate is
+    /// derived from the key and written next to it for inspection.
+    #[clap(long = "key")]
+    key: Option<PathBuf>,
+
+    /// Write each received frame to a numbered file under this directory, so a decoder can
+    /// verify byte-for-byte delivery against the server's --source.
+    #[clap(long)]
+    dump: Option<PathBuf>,
+
+    /// Close the connection if no packet is received for this many milliseconds. Must match
+    /// (or exceed) the server's --idle-timeout-ms for a consistent timeout on both sides.
+    #[clap(long)]
+    idle_timeout_ms: Option<u32>,
+    /// Send a keep-alive packet this often, in milliseconds.
+    #[clap(long)]
+    keep_alive_ms: Option<u64>,
+    /// Abandon the connection attempt if the handshake hasn't completed within this many
+    /// milliseconds.
+    #[clap(long)]
+    handshake_timeout_ms: Option<u64>,
 }
 
 fn main() {
@@ -71,6 +260,7 @@ fn main() {
 #[tokio::main]
 async fn run(options: Opt) -> Result<()> {
     let mut stats = ClientStats::new();
+    let identity = client_identity(&options)?;
     let url = options.url;
     let url_host = strip_ipv6_brackets(url.host_str().unwrap());
     let remote = (url_host, url.port().unwrap_or(4433))
@@ -95,9 +285,15 @@ async fn run(options: Opt) -> Result<()> {
             }
         }
     }
-    let mut client_crypto = rustls::ClientConfig::builder()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
+    let mut client_crypto = match identity {
+        Some((certs, key)) => rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .context("invalid client certificate/key pair")?,
+        None => rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    };
 
     client_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
     if options.keylog {
@@ -112,6 +308,15 @@ async fn run(options: Opt) -> Result<()> {
     // It should instead produce multiple MTU-sized UDP packets before transmission.
     let mut transport_config = quinn::TransportConfig::default();
     transport_config.enable_segmentation_offload(false);
+    if let Some(idle_timeout_ms) = options.idle_timeout_ms {
+        transport_config.max_idle_timeout(Some(
+            quinn::IdleTimeout::try_from(time::Duration::from_millis(idle_timeout_ms.into()))
+                .context("--idle-timeout-ms out of range")?,
+        ));
+    }
+    if let Some(keep_alive_ms) = options.keep_alive_ms {
+        transport_config.keep_alive_interval(Some(time::Duration::from_millis(keep_alive_ms)));
+    }
     client_config.transport_config(Arc::new(transport_config));
     let mut endpoint = quinn::Endpoint::client(options.bind)?;
     endpoint.set_default_client_config(client_config);
@@ -134,11 +339,25 @@ async fn run(options: Opt) -> Result<()> {
     let host = options.host.as_deref().unwrap_or(url_host);
 
     info!("connecting to {host} at {remote}");
-    let conn = endpoint
-        .connect(remote, host)?
-        .await
-        .map_err(|e| anyhow!("failed to connect: {}", e))?;
+    let connecting = endpoint.connect(remote, host)?;
+    let conn = match options.handshake_timeout_ms {
+        Some(timeout_ms) => tokio::time::timeout(time::Duration::from_millis(timeout_ms), connecting)
+            .await
+            .map_err(|_| anyhow!("handshake did not complete within the timeout"))?
+            .map_err(|e| anyhow!("failed to connect: {}", e))?,
+        None => connecting
+            .await
+            .map_err(|e| anyhow!("failed to connect: {}", e))?,
+    };
     info!("connected at {:?}", start.elapsed());
+    if let Some(dir) = options.qlog.clone() {
+        let qlog_conn = conn.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_qlog(qlog_conn, dir).await {
+                error!("qlog task failed: {}", e);
+            }
+        });
+    }
     let (mut send, _) = conn
         .open_bi()
         .await
@@ -157,8 +376,60 @@ async fn run(options: Opt) -> Result<()> {
     let response_start = Instant::now();
     info!("request sent at {:?}", response_start - start);
 
-    // Server sends each frame on a new unidirectional stream. Accept uni streams
-    // instead of bi streams here.
+    let lost_frames = if options.unreliable {
+        recv_frames_datagram(
+            &conn,
+            request_frames,
+            &client_time,
+            &mut stats,
+            options.dump.as_ref(),
+        )
+        .await?
+    } else {
+        recv_frames_stream(
+            &conn,
+            request_frames,
+            &client_time,
+            &mut stats,
+            options.dump.as_ref(),
+        )
+        .await?
+    };
+
+    stats.print_stats();
+    if options.unreliable {
+        println!(
+            "lost {} of {} frames ({:.1}%)",
+            lost_frames,
+            request_frames,
+            lost_frames as f64 / request_frames as f64 * 100.0
+        );
+    }
+    info!("finish the request, closing...");
+
+    conn.close(0u32.into(), b"done");
+
+    tokio::select! {
+        _ = conn.closed() => {
+            info!("connection closed (gracefully)");
+        }
+        _ = endpoint.wait_idle() => {
+            info!("connection closed (drained)");
+        }
+    }
+
+    Ok(())
+}
+
+// Server sends each frame on a new unidirectional stream. Accept uni streams instead of bi
+// streams here. Fully reliable: no frame is ever counted as lost.
+async fn recv_frames_stream(
+    conn: &quinn::Connection,
+    request_frames: usize,
+    client_time: &PeerTime,
+    stats: &mut ClientStats,
+    dump_dir: Option<&PathBuf>,
+) -> Result<usize> {
     let mut recved_frames = 0;
     loop {
         let recv = conn.accept_uni().await;
@@ -193,31 +464,155 @@ async fn run(options: Opt) -> Result<()> {
             client_time.elapsed().as_secs_f64()
         );
 
+        if let Some(dir) = dump_dir {
+            dump_frame(dir, recved_frames, &resp)?;
+        }
+
         recved_frames += 1;
         if recved_frames == request_frames {
             // request finished
             break;
         }
     }
-    // Summary: always print how many frames we actually received vs requested.
     info!(
         "received {} frames (requested {})",
         recved_frames, request_frames
     );
-    stats.print_stats();
-    info!("finish the request, closing...");
+    Ok(0)
+}
 
-    conn.close(0u32.into(), b"done");
+// Server sends each frame fragmented across best-effort DATAGRAMs. Reassemble fragments per
+// frame id; a frame whose fragments don't all arrive within one FRAME_INTERVAL, or before a
+// fragment of a later frame id arrives, is dropped and counted as lost rather than waited on. Returns the
+// number of frames lost.
+async fn recv_frames_datagram(
+    conn: &quinn::Connection,
+    request_frames: usize,
+    client_time: &PeerTime,
+    stats: &mut ClientStats,
+    dump_dir: Option<&PathBuf>,
+) -> Result<usize> {
+    let mut pending: BTreeMap<u32, FrameAssembly> = BTreeMap::new();
+    let mut recved_frames = 0;
+    let mut lost_frames = 0;
+    let mut timeout_tick = tokio::time::interval(FRAME_INTERVAL);
 
-    tokio::select! {
-        _ = conn.closed() => {
-            info!("connection closed (gracefully)");
-        }
-        _ = endpoint.wait_idle() => {
-            info!("connection closed (drained)");
+    while recved_frames + lost_frames < request_frames {
+        tokio::select! {
+            datagram = conn.read_datagram() => {
+                let datagram = match datagram {
+                    Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                        info!("connection closed");
+                        break;
+                    }
+                    Err(e) => {
+                        return Err(anyhow!("failed to read frame datagram: {}", e));
+                    }
+                    Ok(d) => d,
+                };
+                let Some((header, payload)) = DatagramHeader::decode(&datagram) else {
+                    error!("dropping malformed datagram: too short for header");
+                    continue;
+                };
+
+                // A fragment of a later frame id arriving means any still-pending earlier
+                // frame is stale; give up on it rather than waiting forever for stragglers.
+                let stale: Vec<u32> = pending
+                    .range(..header.frame_id)
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in stale {
+                    pending.remove(&id);
+                    lost_frames += 1;
+                }
+
+                let assembly = pending
+                    .entry(header.frame_id)
+                    .or_insert_with(|| FrameAssembly::new(header.fragment_count, Instant::now()));
+                assembly.insert(header.fragment_index, payload.to_vec());
+
+                if assembly.complete() {
+                    let assembly = pending.remove(&header.frame_id).unwrap();
+                    let frame = assembly.into_frame();
+                    stats.bytes_recv(frame.len());
+                    println!(
+                        "frame {}, fin time: {}",
+                        header.frame_id,
+                        client_time.elapsed().as_secs_f64()
+                    );
+                    if let Some(dir) = dump_dir {
+                        dump_frame(dir, recved_frames, &frame)?;
+                    }
+                    recved_frames += 1;
+                }
+            }
+            _ = timeout_tick.tick() => {
+                let now = Instant::now();
+                let expired: Vec<u32> = pending
+                    .iter()
+                    .filter(|(_, assembly)| now.duration_since(assembly.first_seen) >= FRAME_INTERVAL)
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in expired {
+                    pending.remove(&id);
+                    lost_frames += 1;
+                }
+            }
         }
     }
 
+    info!(
+        "received {} frames, lost {} frames (requested {})",
+        recved_frames, lost_frames, request_frames
+    );
+    Ok(lost_frames)
+}
+
+/// Build a client certificate/key pair for mutual TLS from `--cert`/`--key`, if given. With
+/// both set, the PEM files are read directly. With only `--key` set, a self-signed identity is
+/// derived from the key via rcgen (mirroring the server's self-signed fallback) and the
+/// resulting certificate is written next to the key, with the key's extension replaced by
+/// `cert.pem` (e.g. `client.key` -> `client.cert.pem`), so it can be inspected or
+/// reused as `--cert` on a later run.
+fn client_identity(options: &Opt) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> {
+    let Some(key_path) = &options.key else {
+        return Ok(None);
+    };
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .context("failed to read PEM from client key file")?;
+
+    let certs = if let Some(cert_path) = &options.cert {
+        CertificateDer::pem_file_iter(cert_path)
+            .context("failed to read PEM from client certificate file")?
+            .collect::<Result<_, _>>()
+            .context("invalid PEM-encoded client certificate")?
+    } else {
+        info!("deriving self-signed client identity from {}", key_path.display());
+        let key_pem = fs::read_to_string(key_path).context("failed to read client key file")?;
+        let key_pair =
+            rcgen::KeyPair::from_pem(&key_pem).context("failed to parse client key as PEM")?;
+        let cert = rcgen::CertificateParams::new(vec!["localhost".into()])
+            .context("failed to build client certificate params")?
+            .self_signed(&key_pair)
+            .context("failed to self-sign client certificate")?;
+
+        let cert_pem_path = key_path.with_extension("cert.pem");
+        fs::write(&cert_pem_path, cert.pem())
+            .context("failed to write derived client certificate")?;
+
+        vec![CertificateDer::from(cert.der().to_vec())]
+    };
+
+    Ok(Some((certs, key)))
+}
+
+/// Write a received frame to `<dir>/<index:06>.frame`, creating `dir` if needed, for
+/// byte-for-byte delivery verification against the server's `--source`.
+fn dump_frame(dir: &PathBuf, index: usize, frame: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir).context("failed to create frame dump directory")?;
+    let path = dir.join(format!("{index:06}.frame"));
+    fs::write(&path, frame).context("failed to write dumped frame")?;
     Ok(())
 }
 
@@ -18,6 +18,178 @@ use common::{PeerTime, Stats};
 // 30fps. 1 frame every 33ms.
 const FRAME_INTERVAL: time::Duration = time::Duration::from_millis(33);
 
+/// Frame size never shrinks below this, no matter how low the target bitrate falls; below it
+/// we stretch the inter-frame interval instead (effectively lowering fps).
+const MIN_FRAME_SIZE: usize = 200;
+
+/// How much the target bitrate grows per tick when no new loss is observed, in kbps. Additive
+/// increase, mirroring the multiplicative-decrease/additive-increase shape of the CC below it.
+const BITRATE_STEP_KBPS: f64 = 50.0;
+
+/// Tracks a target bitrate for the frame sender and adapts it from `quinn::Connection::stats()`
+/// on each send tick: AIMD, same shape as the transport-level congestion controllers already in
+/// this codebase (e.g. `pemi::cc::Copa`) — multiplicative decrease on loss, additive increase
+/// otherwise, clamped to the operator-configured `--min-kbps`/`--max-kbps` bounds.
+struct BitrateController {
+    target_kbps: f64,
+    min_kbps: f64,
+    max_kbps: f64,
+    last_lost_packets: u64,
+}
+
+impl BitrateController {
+    fn new(min_kbps: f64, max_kbps: f64) -> Self {
+        Self {
+            target_kbps: min_kbps,
+            min_kbps,
+            max_kbps,
+            last_lost_packets: 0,
+        }
+    }
+
+    /// Fold in the latest path stats and return the `(inter-frame interval, frame size)` to
+    /// use for the next frame.
+    fn on_tick(&mut self, stats: &quinn::ConnectionStats) -> (time::Duration, usize) {
+        let lost_packets = stats.path.lost_packets;
+        if lost_packets > self.last_lost_packets {
+            self.target_kbps = (self.target_kbps * 0.85).max(self.min_kbps);
+        } else {
+            self.target_kbps = (self.target_kbps + BITRATE_STEP_KBPS).min(self.max_kbps);
+        }
+        self.last_lost_packets = lost_packets;
+
+        // quinn doesn't expose a bytes_acked path stat directly; cwnd/rtt is the delivery
+        // rate estimate actually available, and serves the same role here.
+        let rtt_secs = stats.path.rtt.as_secs_f64().max(f64::EPSILON);
+        let delivery_kbps = stats.path.cwnd as f64 * 8.0 / 1000.0 / rtt_secs;
+        info!(
+            "bitrate controller: target={:.0}kbps delivery_est={:.0}kbps lost_packets={}",
+            self.target_kbps, delivery_kbps, lost_packets
+        );
+
+        let frame_size =
+            (self.target_kbps * 1000.0 / 8.0 * FRAME_INTERVAL.as_secs_f64()) as usize;
+        if frame_size >= MIN_FRAME_SIZE {
+            (FRAME_INTERVAL, frame_size)
+        } else {
+            // Budget too low even for the minimum frame size: stretch the interval (lower
+            // fps) instead of shrinking the frame further.
+            let interval_secs = MIN_FRAME_SIZE as f64 * 8.0 / (self.target_kbps * 1000.0);
+            (time::Duration::from_secs_f64(interval_secs), MIN_FRAME_SIZE)
+        }
+    }
+}
+
+/// How often the qlog task polls `Connection::stats()` for a `recovery:metrics_updated` event.
+const QLOG_METRICS_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Hand-formatted NDJSON qlog-style event stream for one connection, one file per connection
+/// under `--qlog`'s directory. Mirrors the newline-delimited JSON shape and no-JSON-crate style of
+/// `pemi::qlog`, but quinn doesn't expose a per-packet event hook in its public API, so this
+/// only carries the two categories obtainable from the outside: a `transport:parameters_set`
+/// emitted once after the handshake, and periodic `recovery:metrics_updated` samples polled
+/// from `Connection::stats()` (cwnd/RTT/loss), instead of `packet_sent`/`packet_received`.
+struct Qlog {
+    writer: fs::File,
+    begin: time::Instant,
+}
+
+impl Qlog {
+    fn create(dir: &PathBuf, conn_id: usize, now: time::Instant) -> Result<Self> {
+        fs::create_dir_all(dir).context("failed to create qlog directory")?;
+        let path = dir.join(format!("{conn_id}.qlog.jsonl"));
+        let writer = fs::File::create(&path)
+            .with_context(|| format!("failed to create qlog file {}", path.display()))?;
+        Ok(Self { writer, begin: now })
+    }
+
+    fn emit(&mut self, category: &str, fields: String) {
+        let ts_ms = self.begin.elapsed().as_secs_f64() * 1000.0;
+        if let Err(e) = writeln!(
+            self.writer,
+            r#"{{"ts_ms":{ts_ms:.3},"category":"{category}"{fields}}}"#
+        ) {
+            error!("qlog write failed, dropping event: {}", e);
+        }
+    }
+
+    fn emit_parameters_set(&mut self, remote: SocketAddr, protocol: &str) {
+        self.emit(
+            "transport:parameters_set",
+            format!(r#","remote":"{remote}","alpn":"{protocol}""#),
+        );
+    }
+
+    fn emit_metrics_updated(&mut self, stats: &quinn::ConnectionStats) {
+        self.emit(
+            "recovery:metrics_updated",
+            format!(
+                r#","cwnd":{},"rtt_ms":{:.3},"lost_packets":{},"lost_bytes":{},"sent_packets":{}"#,
+                stats.path.cwnd,
+                stats.path.rtt.as_secs_f64() * 1000.0,
+                stats.path.lost_packets,
+                stats.path.lost_bytes,
+                stats.path.sent_packets,
+            ),
+        );
+    }
+}
+
+/// Poll `conn`'s stats at `QLOG_METRICS_INTERVAL` and emit qlog events under `dir`, until the
+/// connection closes.
+async fn run_qlog(conn: quinn::Connection, dir: PathBuf, protocol: String) -> Result<()> {
+    let mut qlog = Qlog::create(&dir, conn.stable_id(), time::Instant::now())?;
+    qlog.emit_parameters_set(conn.remote_address(), &protocol);
+
+    let mut ticker = tokio::time::interval(QLOG_METRICS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                qlog.emit_metrics_updated(&conn.stats());
+            }
+            _ = conn.closed() => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Extract the subject of the client certificate presented during the handshake, for
+/// attributing logs to an authenticated peer when `--client-ca` is set. Returns
+/// `"<unauthenticated>"` when the connection didn't use mutual TLS (or the identity couldn't be
+/// parsed), rather than failing the connection.
+fn client_cert_subject(conn: &quinn::Connection) -> String {
+    conn.peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certs| certs.first().cloned())
+        .and_then(|cert| {
+            x509_parser::parse_x509_certificate(&cert)
+                .ok()
+                .map(|(_, x509)| x509.subject().to_string())
+        })
+        .unwrap_or_else(|| "<unauthenticated>".to_string())
+}
+
+/// Fixed header prepended to every unreliable frame datagram, so the receiver can reassemble
+/// fragments back into a frame without a QUIC stream to carry ordering/framing for it.
+struct DatagramHeader {
+    frame_id: u32,
+    fragment_index: u16,
+    fragment_count: u16,
+}
+
+const DATAGRAM_HEADER_LEN: usize = 4 + 2 + 2;
+
+impl DatagramHeader {
+    fn encode(&self) -> [u8; DATAGRAM_HEADER_LEN] {
+        let mut buf = [0u8; DATAGRAM_HEADER_LEN];
+        buf[0..4].copy_from_slice(&self.frame_id.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.fragment_index.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.fragment_count.to_be_bytes());
+        buf
+    }
+}
+
 struct MediaClient {
     conn: quinn::Connection,
     request_frames: u64,
@@ -29,11 +201,24 @@ struct MediaClient {
     // for log
     server_time: PeerTime,
 
-    frame_size: usize, // set by command line argument
+    frame_size: usize, // initially the command line argument, then adapted by BitrateController
+
+    // send frames as best-effort QUIC DATAGRAMs instead of one reliable stream per frame
+    unreliable: bool,
+
+    // real media frames to stream in order (looped), in place of synthetic all-zero payloads;
+    // each frame keeps its natural size, so `frame_size`/BitrateController sizing is ignored
+    source: Option<Arc<Vec<Vec<u8>>>>,
 }
 
 impl MediaClient {
-    fn new(conn: quinn::Connection, server_time: PeerTime, frame_size: usize) -> Self {
+    fn new(
+        conn: quinn::Connection,
+        server_time: PeerTime,
+        frame_size: usize,
+        unreliable: bool,
+        source: Option<Arc<Vec<Vec<u8>>>>,
+    ) -> Self {
         Self {
             conn,
             request_frames: 0,
@@ -42,6 +227,17 @@ impl MediaClient {
             stats: Stats::new(),
             server_time,
             frame_size,
+            unreliable,
+            source,
+        }
+    }
+
+    // the next frame's payload: the next frame from --source looped by index, or else a
+    // synthetic all-zero payload sized to `frame_size`
+    fn next_frame_body(&self) -> Vec<u8> {
+        match &self.source {
+            Some(frames) => frames[self.frame_count as usize % frames.len()].clone(),
+            None => vec![0; self.frame_size],
         }
     }
 
@@ -49,12 +245,24 @@ impl MediaClient {
         self.request_frames = request_frames;
     }
 
-    // each frame is sent on a new stream
+    fn set_frame_size(&mut self, frame_size: usize) {
+        self.frame_size = frame_size;
+    }
+
     async fn send_next_frame(&mut self) -> Result<()> {
         if self.all_frames_sent() {
             return Ok(());
         }
-        let body = vec![0; self.frame_size];
+        if self.unreliable {
+            self.send_next_frame_datagram()
+        } else {
+            self.send_next_frame_stream().await
+        }
+    }
+
+    // each frame is sent on a new stream
+    async fn send_next_frame_stream(&mut self) -> Result<()> {
+        let body = self.next_frame_body();
         self.frame_count += 1;
 
         // Open a new unidirectional stream for this frame and send the payload.
@@ -84,6 +292,49 @@ impl MediaClient {
         Ok(())
     }
 
+    // each frame is fragmented across best-effort DATAGRAMs; fragments that never arrive are
+    // simply never retransmitted, matching real-time media's partial-reliability semantics
+    fn send_next_frame_datagram(&mut self) -> Result<()> {
+        let frame_id = self.frame_count as u32 + 1;
+        let body = self.next_frame_body();
+        self.frame_count += 1;
+
+        let max_datagram_size = self
+            .conn
+            .max_datagram_size()
+            .context("peer does not support QUIC DATAGRAMs")?;
+        let max_fragment_len = max_datagram_size
+            .checked_sub(DATAGRAM_HEADER_LEN)
+            .context("negotiated datagram size too small for the fragment header")?;
+
+        let fragment_count = body.len().div_ceil(max_fragment_len) as u16;
+
+        println!(
+            "frame {}, sent time: {}",
+            frame_id,
+            self.server_time.elapsed().as_secs_f64()
+        );
+        self.last_frame_time = time::Instant::now();
+
+        for (fragment_index, chunk) in body.chunks(max_fragment_len).enumerate() {
+            let header = DatagramHeader {
+                frame_id,
+                fragment_index: fragment_index as u16,
+                fragment_count,
+            };
+            let mut datagram = Vec::with_capacity(DATAGRAM_HEADER_LEN + chunk.len());
+            datagram.extend_from_slice(&header.encode());
+            datagram.extend_from_slice(chunk);
+
+            self.conn
+                .send_datagram(datagram.into())
+                .map_err(|e| anyhow!("failed to send frame datagram: {}", e))?;
+        }
+
+        self.stats.bytes_sent(body.len());
+        Ok(())
+    }
+
     fn all_frames_sent(&self) -> bool {
         assert!(self.frame_count <= self.request_frames);
         self.frame_count == self.request_frames
@@ -122,6 +373,68 @@ struct Opt {
     /// Frame size
     #[clap(short, long, default_value = "12500")]
     frame_size: usize,
+    /// Send frames as best-effort QUIC DATAGRAMs, fragmented and reassembled per frame id,
+    /// instead of a reliable stream per frame. Late fragments are dropped rather than waited
+    /// on, so this trades reliability for real-time-media-style partial reliability.
+    #[clap(long)]
+    unreliable: bool,
+    /// Lower bound for the adaptive bitrate controller, in kbps
+    #[clap(long, default_value = "500.0")]
+    min_kbps: f64,
+    /// Upper bound for the adaptive bitrate controller, in kbps
+    #[clap(long, default_value = "5000.0")]
+    max_kbps: f64,
+    /// Write a qlog-style NDJSON event file per connection to this directory
+    #[clap(long)]
+    qlog: Option<PathBuf>,
+    /// Require and verify client certificates against this PEM trust root, enabling mutual TLS
+    #[clap(long = "client-ca")]
+    client_ca: Option<PathBuf>,
+    /// Stream frames read from this media dump instead of synthetic all-zero payloads: a
+    /// sequence of u32 big-endian length-prefixed frames, looped if fewer frames exist than
+    /// requested. Each frame keeps its natural size; --frame-size is ignored.
+    #[clap(long)]
+    source: Option<PathBuf>,
+    /// Close a connection if no packet is received for this many milliseconds. Must be
+    /// configured before the connection is accepted: the GetN request wait and the gaps
+    /// between frame bursts at low frame rates can otherwise look like an idle connection to
+    /// quinn's default idle timeout and get the session reaped mid-experiment.
+    #[clap(long)]
+    idle_timeout_ms: Option<u32>,
+    /// Send a keep-alive packet this often, in milliseconds, to hold the path open through
+    /// silent gaps (e.g. while waiting for the client's GetN request) without relying solely on
+    /// the periodic frame ticker. Should be set well below --idle-timeout-ms.
+    #[clap(long)]
+    keep_alive_ms: Option<u64>,
+    /// Abandon a connection attempt that hasn't completed its handshake within this many
+    /// milliseconds.
+    #[clap(long)]
+    handshake_timeout_ms: Option<u64>,
+}
+
+/// Read a length-prefixed media dump (u32 big-endian frame length, then that many bytes,
+/// repeated to EOF) into memory so every connection can stream from it without re-reading the
+/// file. Errors if the dump is empty, since `MediaClient` loops over it by index modulo length.
+fn read_frame_source(path: &PathBuf) -> Result<Vec<Vec<u8>>> {
+    let data = fs::read(path).context("failed to read media source file")?;
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let len_bytes = data
+            .get(offset..offset + 4)
+            .context("media source file truncated: expected a frame length prefix")?;
+        let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        offset += 4;
+        let frame = data
+            .get(offset..offset + len)
+            .context("media source file truncated: frame shorter than its length prefix")?;
+        frames.push(frame.to_vec());
+        offset += len;
+    }
+    if frames.is_empty() {
+        bail!("media source file contains no frames");
+    }
+    Ok(frames)
 }
 
 fn main() {
@@ -194,9 +507,28 @@ async fn run(options: Opt) -> Result<()> {
         (vec![cert], key)
     };
 
-    let mut server_crypto = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+    let mut server_crypto = if let Some(client_ca_path) = &options.client_ca {
+        let mut client_roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(client_ca_path)
+            .context("failed to read PEM from client CA file")?
+        {
+            client_roots
+                .add(cert.context("invalid PEM-encoded client CA certificate")?)
+                .context("failed to add client CA to the trust root")?;
+        }
+        let client_cert_verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(
+            client_roots,
+        ))
+        .build()
+        .context("failed to build client certificate verifier")?;
+        rustls::ServerConfig::builder()
+            .with_client_cert_verifier(client_cert_verifier)
+            .with_single_cert(certs, key)?
+    } else {
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?
+    };
     server_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
     if options.keylog {
         server_crypto.key_log = Arc::new(rustls::KeyLogFile::new());
@@ -212,6 +544,26 @@ async fn run(options: Opt) -> Result<()> {
     // It should instead produce multiple MTU-sized UDP packets before transmission.
     transport_config.enable_segmentation_offload(false);
 
+    // These must be set before `endpoint.accept()` below: quinn applies the transport config
+    // as soon as a connection is accepted, so a server-side keep-alive configured only after
+    // the fact would never cover the GetN request wait on an already-open connection.
+    if let Some(idle_timeout_ms) = options.idle_timeout_ms {
+        transport_config.max_idle_timeout(Some(
+            quinn::IdleTimeout::try_from(time::Duration::from_millis(idle_timeout_ms.into()))
+                .context("--idle-timeout-ms out of range")?,
+        ));
+    }
+    if let Some(keep_alive_ms) = options.keep_alive_ms {
+        transport_config.keep_alive_interval(Some(time::Duration::from_millis(keep_alive_ms)));
+    }
+
+    let source = options
+        .source
+        .as_ref()
+        .map(read_frame_source)
+        .transpose()?
+        .map(Arc::new);
+
     let endpoint = quinn::Endpoint::server(server_config, options.listen)?;
     eprintln!("Listening on {}", endpoint.local_addr()?);
 
@@ -230,7 +582,18 @@ async fn run(options: Opt) -> Result<()> {
             conn.retry().unwrap();
         } else {
             info!("accepting connection");
-            let fut = handle_connection(conn, options.frame_size);
+            let fut = handle_connection(
+                conn,
+                ConnectionOpts {
+                    frame_size: options.frame_size,
+                    unreliable: options.unreliable,
+                    min_kbps: options.min_kbps,
+                    max_kbps: options.max_kbps,
+                    qlog_dir: options.qlog.clone(),
+                    source: source.clone(),
+                    handshake_timeout: options.handshake_timeout_ms.map(time::Duration::from_millis),
+                },
+            );
             tokio::spawn(async move {
                 if let Err(e) = fut.await {
                     error!("connection failed: {reason}", reason = e.to_string())
@@ -242,23 +605,67 @@ async fn run(options: Opt) -> Result<()> {
     Ok(())
 }
 
-async fn handle_connection(conn: quinn::Incoming, frame_size: usize) -> Result<()> {
-    let connection = conn.await?;
-    let span: tracing::Span = info_span!(
+/// Per-connection settings that stay the same across every connection a server instance
+/// accepts, grouped to keep `handle_connection`'s signature manageable.
+struct ConnectionOpts {
+    frame_size: usize,
+    unreliable: bool,
+    min_kbps: f64,
+    max_kbps: f64,
+    qlog_dir: Option<PathBuf>,
+    source: Option<Arc<Vec<Vec<u8>>>>,
+    handshake_timeout: Option<time::Duration>,
+}
+
+async fn handle_connection(conn: quinn::Incoming, opts: ConnectionOpts) -> Result<()> {
+    let ConnectionOpts {
+        frame_size,
+        unreliable,
+        min_kbps,
+        max_kbps,
+        qlog_dir,
+        source,
+        handshake_timeout,
+    } = opts;
+    let connection = match handshake_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, conn)
+            .await
+            .map_err(|_| anyhow!("handshake did not complete within the timeout"))??,
+        None => conn.await?,
+    };
+    let protocol = connection
+        .handshake_data()
+        .unwrap()
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .unwrap()
+        .protocol
+        .map_or_else(|| "<none>".into(), |x| String::from_utf8_lossy(&x).into_owned());
+    let client = client_cert_subject(&connection);
+    let span = info_span!(
         "connection",
         remote = %connection.remote_address(),
-        protocol = %connection
-            .handshake_data()
-            .unwrap()
-            .downcast::<quinn::crypto::rustls::HandshakeData>().unwrap()
-            .protocol
-            .map_or_else(|| "<none>".into(), |x| String::from_utf8_lossy(&x).into_owned())
+        protocol = %protocol,
+        client = %client,
     );
+    if let Some(dir) = qlog_dir {
+        let qlog_conn = connection.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_qlog(qlog_conn, dir, protocol).await {
+                error!("qlog task failed: {}", e);
+            }
+        });
+    }
     async {
         info!("established");
         let request_frame_num: usize;
         // Wait the rtc request
-        let mut client = MediaClient::new(connection.clone(), PeerTime::new(&0.0), frame_size);
+        let mut client = MediaClient::new(
+            connection.clone(),
+            PeerTime::new(&0.0),
+            frame_size,
+            unreliable,
+            source,
+        );
         loop {
             let stream = connection.accept_bi().await;
             let mut stream = match stream {
@@ -291,11 +698,24 @@ async fn handle_connection(conn: quinn::Incoming, frame_size: usize) -> Result<(
         client.stats.request_recved();
         client.set_request_frames(request_frame_num as u64);
 
-        // Send frames periodically at FRAME_INTERVAL
-        let mut ticker = tokio::time::interval(FRAME_INTERVAL);
+        // Poll at FRAME_INTERVAL granularity, but only actually send once `next_send_due`
+        // elapses: the bitrate controller can stretch the real inter-frame interval beyond
+        // FRAME_INTERVAL when the path can't sustain even a minimum-size frame that often.
+        let mut poll_ticker = tokio::time::interval(FRAME_INTERVAL);
+        let mut bitrate = BitrateController::new(min_kbps, max_kbps);
+        let mut next_send_due = time::Instant::now();
 
         while !client.all_frames_sent() {
-            ticker.tick().await;
+            poll_ticker.tick().await;
+            let now = time::Instant::now();
+            if now < next_send_due {
+                continue;
+            }
+
+            let (interval, frame_size) = bitrate.on_tick(&connection.stats());
+            client.set_frame_size(frame_size);
+            next_send_due = now + interval;
+
             if let Err(e) = client.send_next_frame().await {
                 error!("failed to send frame: {}", e);
                 break;
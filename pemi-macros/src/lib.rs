@@ -0,0 +1,296 @@
+//! Derive macros that generate `octets`-based read/write glue for QUIC structs, so
+//! header and frame types in `pemi` don't need to hand-write the kind of per-field
+//! parsing `pemi::quic_parse::Header::from_bytes` does.
+//!
+//! `#[derive(ReadQuic)]` generates a `read_quic(b: &mut octets::Octets) -> Result<Self,
+//! pemi::common::Error>` inherent method that reads each field, in declaration order,
+//! off `b`. `#[derive(WriteQuic)]` generates the matching `write_quic(&self, b: &mut
+//! octets::OctetsMut) -> Result<(), pemi::common::Error>`.
+//!
+//! Plain `u8`/`u16`/`u32`/`u64` fields read/write a fixed-width integer. Other field
+//! shapes need a `#[quic(..)]` attribute:
+//!
+//! - `#[quic(varint)]` - an RFC 9000 section 16 variable-length integer (field type
+//!   must be `u64`).
+//! - `#[quic(len = "other_field")]` - a byte slice whose length was already read into
+//!   `other_field` (field type must be `Vec<u8>`; `other_field` must be an earlier
+//!   field in the struct).
+//! - `#[quic(remaining)]` - every byte left in the buffer (field type must be
+//!   `Vec<u8>`; only valid on the last field).
+//!
+//! Generated reads produce `pemi::common::Error` carrying the same `ErrorKind::Truncated`
+//! offset/needed context `quic_parse`'s hand-written wrappers attach, tagged via
+//! `push_context("<Struct>", "<field>")` so a failure inside a derived struct shows up
+//! in `Display` the same way a failure inside hand-written parsing does.
+//!
+//! The generated `read_quic`/`write_quic` bodies use `__b`/`__offset`/`__needed` as
+//! internal names (rather than the more obvious `b`/`offset`/`needed`) since `quote!`
+//! output isn't hygienic against a struct field happening to share one of those names.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, punctuated::Punctuated, Data, DeriveInput, Field, Fields, Ident, LitStr,
+    Token, Type,
+};
+
+#[proc_macro_derive(ReadQuic, attributes(quic))]
+pub fn derive_read_quic(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_read_quic(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(WriteQuic, attributes(quic))]
+pub fn derive_write_quic(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_write_quic(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// What a field's `#[quic(..)]` attribute (if any) said to do.
+enum FieldKind {
+    /// No attribute: a fixed-width integer matching the field's Rust type.
+    Fixed,
+    Varint,
+    /// Names the earlier field holding this field's byte length.
+    Len(Ident),
+    Remaining,
+}
+
+fn field_kind(field: &Field) -> syn::Result<FieldKind> {
+    let mut kind = None;
+    for attr in &field.attrs {
+        if !attr.path().is_ident("quic") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("varint") {
+                kind = Some(FieldKind::Varint);
+                Ok(())
+            } else if meta.path.is_ident("remaining") {
+                kind = Some(FieldKind::Remaining);
+                Ok(())
+            } else if meta.path.is_ident("len") {
+                let lit: LitStr = meta.value()?.parse()?;
+                kind = Some(FieldKind::Len(Ident::new(&lit.value(), lit.span())));
+                Ok(())
+            } else {
+                Err(meta.error("unknown #[quic(..)] option"))
+            }
+        })?;
+    }
+    Ok(kind.unwrap_or(FieldKind::Fixed))
+}
+
+fn named_fields(input: &DeriveInput) -> syn::Result<&Punctuated<Field, Token![,]>> {
+    match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(named) => Ok(&named.named),
+            _ => Err(syn::Error::new(
+                input.span(),
+                "ReadQuic/WriteQuic only support structs with named fields",
+            )),
+        },
+        _ => Err(syn::Error::new(
+            input.span(),
+            "ReadQuic/WriteQuic only support structs",
+        )),
+    }
+}
+
+/// Maps a plain (no `#[quic(..)]`) field's Rust type to the `octets` accessor suffix
+/// and its fixed width in bytes, e.g. `u32` -> (`"u32"`, 4).
+fn fixed_width(ty: &Type) -> syn::Result<(&'static str, usize)> {
+    if let Type::Path(p) = ty {
+        if let Some(seg) = p.path.segments.last() {
+            return match seg.ident.to_string().as_str() {
+                "u8" => Ok(("u8", 1)),
+                "u16" => Ok(("u16", 2)),
+                "u32" => Ok(("u32", 4)),
+                "u64" => Ok(("u64", 8)),
+                _ => Err(syn::Error::new(
+                    ty.span(),
+                    "unsupported field type for a plain #[quic] field; use u8/u16/u32/u64, \
+                     or #[quic(varint)]/#[quic(len = \"..\")]/#[quic(remaining)]",
+                )),
+            };
+        }
+    }
+    Err(syn::Error::new(ty.span(), "unsupported field type"))
+}
+
+fn expand_read_quic(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = named_fields(input)?;
+    let name = &input.ident;
+    let struct_name = name.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let n_fields = fields.len();
+    let mut reads = Vec::with_capacity(n_fields);
+    let mut idents = Vec::with_capacity(n_fields);
+
+    for (i, field) in fields.iter().enumerate() {
+        let ident = field.ident.as_ref().expect("named field");
+        let field_name = ident.to_string();
+        idents.push(ident);
+
+        let read = match field_kind(field)? {
+            FieldKind::Fixed => {
+                let (suffix, width) = fixed_width(&field.ty)?;
+                let getter = Ident::new(&format!("get_{suffix}"), field.span());
+                quote! {
+                    let __offset = __b.off();
+                    let __needed = (#width).saturating_sub(__b.cap());
+                    let #ident = __b.#getter().map_err(|_| {
+                        pemi::common::Error::from(pemi::common::ErrorKind::Truncated {
+                            offset: __offset,
+                            needed: __needed,
+                        })
+                        .push_context(#struct_name, #field_name)
+                    })?;
+                }
+            }
+            FieldKind::Varint => quote! {
+                let __offset = __b.off();
+                // Mirrors quic_parse::get_varint: peek the leading byte to learn the
+                // varint's actual encoded width (RFC 9000 section 16's top two bits) before
+                // falling back to 1 when even that peek fails.
+                let __needed = match __b.peek_u8() {
+                    Ok(__first) => (match __first >> 6 {
+                        0b00 => 1usize,
+                        0b01 => 2,
+                        0b10 => 4,
+                        _ => 8,
+                    })
+                    .saturating_sub(__b.cap()),
+                    Err(_) => 1,
+                };
+                let #ident = __b.get_varint().map_err(|_| {
+                    pemi::common::Error::from(pemi::common::ErrorKind::Truncated {
+                        offset: __offset,
+                        needed: __needed,
+                    })
+                    .push_context(#struct_name, #field_name)
+                })?;
+            },
+            FieldKind::Len(len_field) => quote! {
+                let __offset = __b.off();
+                let __needed = (#len_field as usize).saturating_sub(__b.cap());
+                let #ident = __b
+                    .get_bytes(#len_field as usize)
+                    .map_err(|_| {
+                        pemi::common::Error::from(pemi::common::ErrorKind::Truncated {
+                            offset: __offset,
+                            needed: __needed,
+                        })
+                        .push_context(#struct_name, #field_name)
+                    })?
+                    .to_vec();
+            },
+            FieldKind::Remaining => {
+                if i != n_fields - 1 {
+                    return Err(syn::Error::new(
+                        field.span(),
+                        "#[quic(remaining)] is only valid on the last field",
+                    ));
+                }
+                quote! {
+                    let #ident = __b
+                        .get_bytes(__b.cap())
+                        .map_err(|_| {
+                            pemi::common::Error::from(pemi::common::ErrorKind::InvalidPacket)
+                                .push_context(#struct_name, #field_name)
+                        })?
+                        .to_vec();
+                }
+            }
+        };
+        reads.push(read);
+    }
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Reads each field, in declaration order, off `b`. Generated by
+            /// `#[derive(ReadQuic)]`; see that macro's doc comment for the field
+            /// attributes it understands.
+            pub fn read_quic(__b: &mut octets::Octets) -> Result<Self, pemi::common::Error> {
+                #(#reads)*
+                Ok(Self { #(#idents),* })
+            }
+        }
+    })
+}
+
+fn expand_write_quic(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = named_fields(input)?;
+    let name = &input.ident;
+    let struct_name = name.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let n_fields = fields.len();
+    let mut writes = Vec::with_capacity(n_fields);
+
+    for (i, field) in fields.iter().enumerate() {
+        let ident = field.ident.as_ref().expect("named field");
+        let field_name = ident.to_string();
+
+        let write = match field_kind(field)? {
+            FieldKind::Fixed => {
+                let (suffix, _width) = fixed_width(&field.ty)?;
+                let putter = Ident::new(&format!("put_{suffix}"), field.span());
+                quote! {
+                    __b.#putter(self.#ident).map_err(|_| {
+                        pemi::common::Error::from(pemi::common::ErrorKind::InvalidPacket)
+                            .push_context(#struct_name, #field_name)
+                    })?;
+                }
+            }
+            FieldKind::Varint => quote! {
+                __b.put_varint(self.#ident).map_err(|_| {
+                    pemi::common::Error::from(pemi::common::ErrorKind::InvalidPacket)
+                        .push_context(#struct_name, #field_name)
+                })?;
+            },
+            FieldKind::Len(_) => quote! {
+                __b.put_bytes(&self.#ident).map_err(|_| {
+                    pemi::common::Error::from(pemi::common::ErrorKind::InvalidPacket)
+                        .push_context(#struct_name, #field_name)
+                })?;
+            },
+            FieldKind::Remaining => {
+                if i != n_fields - 1 {
+                    return Err(syn::Error::new(
+                        field.span(),
+                        "#[quic(remaining)] is only valid on the last field",
+                    ));
+                }
+                quote! {
+                    __b.put_bytes(&self.#ident).map_err(|_| {
+                        pemi::common::Error::from(pemi::common::ErrorKind::InvalidPacket)
+                            .push_context(#struct_name, #field_name)
+                    })?;
+                }
+            }
+        };
+        writes.push(write);
+    }
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Writes each field, in declaration order, into `b`. Generated by
+            /// `#[derive(WriteQuic)]`; see that macro's doc comment for the field
+            /// attributes it understands.
+            pub fn write_quic(&self, __b: &mut octets::OctetsMut) -> Result<(), pemi::common::Error> {
+                #(#writes)*
+                Ok(())
+            }
+        }
+    })
+}
@@ -9,27 +9,313 @@ use {
     std::arch::x86_64::_rdtsc,
 };
 
-use std::io::IoSliceMut;
 use std::io::Write;
-use std::net::UdpSocket;
-use std::os::fd::AsRawFd;
+use std::net::{Ipv6Addr, SocketAddrV6, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time;
 
-use nix::sys::socket::sockopt::{IpTransparent, Ipv4OrigDstAddr, ReuseAddr};
-use nix::sys::socket::{recvmsg, setsockopt, MsgFlags, RecvMsg, SockaddrIn};
+use nix::sys::socket::sockopt::{Ipv6Transparent, Ipv6V6Only, ReuseAddr, ReusePort};
+use nix::sys::socket::{bind, setsockopt, socket, AddressFamily, SockFlag, SockType, SockaddrIn6};
 
 use log::{debug, trace};
 
 use clap::Parser;
 
-const MAX_RECV_BUF: usize = 1500;
+/// `UDP_GRO` can hand back many coalesced datagrams in one receive; 64 KiB is the largest a
+/// `UDP_GRO` buffer can be (the kernel caps coalescing there), so this sizes for the worst case
+/// rather than the single-datagram `MAX_RECV_BUF = 1500` of before GRO.
+const MAX_RECV_BUF: usize = 65536;
+
+/// Max datagrams drained per `recvmmsg` call. One `socket.readable()` wakeup can have many
+/// datagrams queued under load; this caps how many come back in a single syscall so the loop
+/// still gets to service `pemi.timeout()`/the RTT detector between batches.
+const RECV_BATCH: usize = 32;
+
+/// Size of the control-message buffer per slot, sized for one `IPV6_ORIGDSTADDR` cmsg plus one
+/// `UDP_GRO` cmsg.
+const RECV_CMSG_BUF: usize = 128;
+
+/// `SOL_UDP`/`UDP_GRO` aren't bound by the `nix` sockopt/cmsg types PEMI otherwise uses (same
+/// reasoning as `pemi_io::UDP_SEGMENT` on the send side), so this is set via raw `libc`.
+const SOL_UDP: libc::c_int = 17;
+const UDP_GRO: libc::c_int = 104;
+
+/// `IPV6_RECVORIGDSTADDR`/`IPV6_ORIGDSTADDR` share the same option number (74, per
+/// `linux/in6.h`, the same way `IP_RECVORIGDSTADDR`/`IP_ORIGDSTADDR` share 20 for v4), so one
+/// constant serves both the `setsockopt` that enables it and the cmsg type it shows up as.
+/// Not wrapped by `nix`, same reasoning as `UDP_GRO`.
+const IPV6_ORIGDSTADDR: libc::c_int = 74;
+
+/// One pre-allocated datagram buffer, control-message buffer, and source-address slot, reused
+/// across `recvmmsg` calls instead of allocating fresh `IoSliceMut`s/cmsg space every wakeup.
+/// The listening socket is a single dual-stack (`IPV6_V6ONLY` off) `AF_INET6` socket (see
+/// `bind_reuseport_socket`), so `recvmmsg` always reports addresses as `sockaddr_in6` even for
+/// v4-mapped peers; there's no separate v4 `RecvSlot` path.
+struct RecvSlot {
+    buf: [u8; MAX_RECV_BUF],
+    cmsg: [u8; RECV_CMSG_BUF],
+    src: libc::sockaddr_in6,
+}
+
+impl RecvSlot {
+    fn new() -> Self {
+        RecvSlot {
+            buf: [0u8; MAX_RECV_BUF],
+            cmsg: [0u8; RECV_CMSG_BUF],
+            src: unsafe { std::mem::zeroed() },
+        }
+    }
+}
+
+/// One QUIC datagram, already split out of its (possibly `UDP_GRO`-coalesced) `recvmmsg`
+/// message: its payload plus the source/original-destination addresses the message reported,
+/// shared by every datagram `UDP_GRO` folded into that one message.
+struct RecvMmsgResult {
+    buf: Vec<u8>,
+    srcaddr: pemi_io::NixAddr,
+    dstaddr: pemi_io::NixAddr,
+}
+
+/// Drain up to `slots.len()` messages from `fd` in one `recvmmsg` syscall, each with its own
+/// `iovec` and `IPV6_ORIGDSTADDR`/`UDP_GRO` control-message buffer, the same
+/// completion-style batching `pemi_io::send_transparently_batch` uses for sends. `nix` wraps
+/// `recvmmsg`, but its `MultiHeaderStorage` type doesn't fit PEMI's plain
+/// preallocated-array-of-slots style as directly as going straight to `libc` does (the same
+/// reasoning `send_transparently_batch` already applies to `sendmmsg`), so this builds the
+/// `mmsghdr` array by hand. A message the kernel coalesced under `UDP_GRO` is split back into
+/// its individual `gso_size`-byte segments (final segment may be shorter) before being handed
+/// back, so one result is always exactly one datagram. Returns `Ok(vec![])` on `EAGAIN`
+/// (readable() woke us up but nothing was actually queued by the time we read).
+fn recv_batch(fd: RawFd, slots: &mut [RecvSlot]) -> std::io::Result<Vec<RecvMmsgResult>> {
+    let mut iovecs: Vec<libc::iovec> = slots
+        .iter_mut()
+        .map(|slot| libc::iovec {
+            iov_base: slot.buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: slot.buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = slots
+        .iter_mut()
+        .zip(iovecs.iter_mut())
+        .map(|(slot, iov)| libc::mmsghdr {
+            msg_hdr: libc::msghdr {
+                msg_name: &mut slot.src as *mut libc::sockaddr_in6 as *mut libc::c_void,
+                msg_namelen: std::mem::size_of::<libc::sockaddr_in6>() as u32,
+                msg_iov: iov as *mut libc::iovec,
+                msg_iovlen: 1,
+                msg_control: slot.cmsg.as_mut_ptr() as *mut libc::c_void,
+                msg_controllen: slot.cmsg.len(),
+                msg_flags: 0,
+            },
+            msg_len: 0,
+        })
+        .collect();
+
+    let n = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as u32,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::WouldBlock {
+            return Ok(Vec::new());
+        }
+        return Err(err);
+    }
+
+    let mut results = Vec::with_capacity(n as usize);
+    for (slot, msg) in slots.iter().zip(msgs.iter()).take(n as usize) {
+        let len = msg.msg_len as usize;
+        let srcaddr = pemi_io::NixAddr::V6(SockaddrIn6::from(slot.src));
+        let orig_dst = orig_dstaddr_from_cmsg(&msg.msg_hdr)
+            .unwrap_or_else(|| panic!("no original destination address in message"));
+        let dstaddr = pemi_io::NixAddr::V6(SockaddrIn6::from(orig_dst));
+        let gso_size = gro_size_from_cmsg(&msg.msg_hdr);
+        for segment in split_gro_segments(&slot.buf[..len], gso_size) {
+            results.push(RecvMmsgResult {
+                buf: segment,
+                srcaddr,
+                dstaddr,
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// Walk `msg_hdr`'s control messages looking for `IPV6_ORIGDSTADDR` (the raw-`libc` counterpart
+/// of `nix`'s `ControlMessageOwned::Ipv6OrigDstAddr`, which only comes from `nix::recvmsg`).
+/// The listening socket is dual-stack, so this is the only original-destination cmsg type that
+/// shows up, for both real v6 peers and v4-mapped ones.
+fn orig_dstaddr_from_cmsg(msg_hdr: &libc::msghdr) -> Option<libc::sockaddr_in6> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg_hdr);
+        while !cmsg.is_null() {
+            let hdr = &*cmsg;
+            if hdr.cmsg_level == libc::IPPROTO_IPV6 && hdr.cmsg_type == IPV6_ORIGDSTADDR {
+                let data = libc::CMSG_DATA(cmsg) as *const libc::sockaddr_in6;
+                return Some(*data);
+            }
+            cmsg = libc::CMSG_NXTHDR(msg_hdr, cmsg);
+        }
+    }
+    None
+}
+
+/// Walk `msg_hdr`'s control messages looking for `UDP_GRO`'s `gso_size`, the segment size the
+/// kernel coalesced this message's datagrams to. `None` means the message is a single,
+/// uncoalesced datagram.
+fn gro_size_from_cmsg(msg_hdr: &libc::msghdr) -> Option<u16> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg_hdr);
+        while !cmsg.is_null() {
+            let hdr = &*cmsg;
+            if hdr.cmsg_level == SOL_UDP && hdr.cmsg_type == UDP_GRO {
+                let data = libc::CMSG_DATA(cmsg) as *const u16;
+                return Some(data.read_unaligned());
+            }
+            cmsg = libc::CMSG_NXTHDR(msg_hdr, cmsg);
+        }
+    }
+    None
+}
+
+/// Split a `recvmmsg` message's payload back into individual datagrams along `gso_size`
+/// boundaries (the final segment may be shorter than `gso_size`); `None`/zero means the
+/// message wasn't `UDP_GRO`-coalesced and is already exactly one datagram.
+fn split_gro_segments(buf: &[u8], gso_size: Option<u16>) -> Vec<Vec<u8>> {
+    match gso_size {
+        Some(gso_size) if gso_size > 0 && (gso_size as usize) < buf.len() => {
+            buf.chunks(gso_size as usize).map(|c| c.to_vec()).collect()
+        }
+        _ => vec![buf.to_vec()],
+    }
+}
+
+/// Create and bind a dual-stack listening socket for one `--workers` shard, with
+/// `SO_REUSEADDR`, `SO_REUSEPORT`, `IPV6_TRANSPARENT`, and `IPV6_RECVORIGDSTADDR` all set
+/// before `bind`, since the kernel only honors `SO_REUSEPORT` for sockets that had it set at
+/// bind time. `std::net`'s `UdpSocket::bind` creates and binds in one call with no hook to set
+/// options in between, so this goes through `nix::sys::socket::{socket, bind}` directly, the
+/// same ordering `pemi_io::create_transparent_socket` already uses for PEMI's transparent-send
+/// sockets.
+///
+/// Binding `AF_INET6` with `IPV6_V6ONLY` off rather than a separate `AF_INET` socket lets one
+/// listener serve both v4 (arriving v4-mapped) and v6 clients, so there's only one shard per
+/// worker instead of needing to fan a v4 and a v6 socket out to the same `PEMI`.
+fn bind_reuseport_socket(port: u16) -> Result<UdpSocket, String> {
+    let fd = socket(AddressFamily::Inet6, SockType::Datagram, SockFlag::empty(), None)
+        .map_err(|e| format!("error creating listening socket: {}", e))?;
+    setsockopt(&fd, ReuseAddr, &true).map_err(|e| format!("error setting SO_REUSEADDR: {}", e))?;
+    setsockopt(&fd, ReusePort, &true).map_err(|e| format!("error setting SO_REUSEPORT: {}", e))?;
+    setsockopt(&fd, Ipv6V6Only, &false)
+        .map_err(|e| format!("error clearing IPV6_V6ONLY: {}", e))?;
+    setsockopt(&fd, Ipv6Transparent, &true)
+        .map_err(|e| format!("error setting IPV6_TRANSPARENT: {}", e))?;
+    set_ipv6_recvorigdstaddr(fd.as_raw_fd())
+        .map_err(|e| format!("error setting IPV6_RECVORIGDSTADDR: {}", e))?;
+    let addr = SockaddrIn6::from(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0));
+    bind(fd.as_raw_fd(), &addr).map_err(|e| format!("error binding to port {}: {}", port, e))?;
+    Ok(UdpSocket::from(fd))
+}
+
+/// Enable `IPV6_RECVORIGDSTADDR` on `fd`, so the transparently-forwarded destination shows up
+/// as an `IPV6_ORIGDSTADDR` cmsg on every received message (see `orig_dstaddr_from_cmsg`). Not
+/// wrapped by `nix`'s sockopt types, same reasoning as `UDP_GRO`.
+fn set_ipv6_recvorigdstaddr(fd: RawFd) -> std::io::Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            IPV6_ORIGDSTADDR,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enable `UDP_GRO` on `socket`, so the kernel coalesces back-to-back same-source datagrams
+/// into one larger `recvmmsg` message (see `split_gro_segments`) instead of one message per
+/// datagram. Not wrapped by `nix`'s sockopt types, same reasoning as `UDP_SEGMENT` on send.
+fn set_udp_gro(socket: &UdpSocket) -> Result<(), String> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            SOL_UDP,
+            UDP_GRO,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(format!(
+            "error setting UDP_GRO: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// `--cc` choices, mirroring `pemi::cc::RetransCcAlgo` (kept separate so `pemi::cc` doesn't need
+/// to depend on `clap`).
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum CcArg {
+    Reno,
+    Cubic,
+}
+
+impl From<CcArg> for pemi::cc::RetransCcAlgo {
+    fn from(arg: CcArg) -> Self {
+        match arg {
+            CcArg::Reno => pemi::cc::RetransCcAlgo::Reno,
+            CcArg::Cubic => pemi::cc::RetransCcAlgo::Cubic,
+        }
+    }
+}
+
+/// `--conn-cc` choices, mirroring `pemi::cc::ConnCcAlgo` (kept separate so `pemi::cc` doesn't
+/// need to depend on `clap`).
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ConnCcArg {
+    Copa,
+    Reno,
+    Cubic,
+}
 
-#[derive(Parser)]
+impl From<ConnCcArg> for pemi::cc::ConnCcAlgo {
+    fn from(arg: ConnCcArg) -> Self {
+        match arg {
+            ConnCcArg::Copa => pemi::cc::ConnCcAlgo::Copa,
+            ConnCcArg::Reno => pemi::cc::ConnCcAlgo::Reno,
+            ConnCcArg::Cubic => pemi::cc::ConnCcAlgo::Cubic,
+        }
+    }
+}
+
+#[derive(Parser, Clone)]
 struct Args {
     /// port number, default is 5000
     #[clap(short, long, default_value = "5000")]
     port: u16,
 
+    /// Number of shards to run, each its own PEMI instance on its own `SO_REUSEPORT` socket and
+    /// tokio task, so the kernel's reuseport hashing pins each flow to one shard and PEMI scales
+    /// past a single core. Default of 1 keeps the single-socket behavior from before sharding.
+    #[clap(short, long, default_value = "1")]
+    workers: usize,
+
     /// FLOWLET_INTERVAL_FACTOR. The factor of the flowlet timeout(to decide whether to create new flowlet.
     #[clap(short, long, default_value = "2.0")]
     fl_inv_factor: f64,
@@ -38,6 +324,11 @@ struct Args {
     #[clap(short, long, default_value = "0.5")]
     fl_end_factor: f64,
 
+    /// Divisor N in the delayed-ack packet threshold max(2, cwnd_pkts / N): reordered ACKs
+    /// are released once more than 1/N of a congestion window's worth of them are queued.
+    #[clap(long, default_value = "4.0")]
+    ack_freq_divisor: f64,
+
     /// Frequency to print the stats.(every N packets)
     #[clap(short, long, default_value = "1000")]
     print_interval: u64,
@@ -45,50 +336,141 @@ struct Args {
     /// Is set as True, only transparent forwarding. (not enable PEMI)
     #[clap(short, long)]
     proxy_only: bool,
+
+    /// Congestion-window growth curve for injected retransmissions.
+    #[clap(long, value_enum, default_value_t = CcArg::Cubic)]
+    cc: CcArg,
+
+    /// Congestion-control backend driving each connection's overspeed/pacing decision on the
+    /// forwarding path.
+    #[clap(long, value_enum, default_value_t = ConnCcArg::Copa)]
+    conn_cc: ConnCcArg,
+
+    /// Path to write a qlog-style JSON-lines event stream to (connection lifecycle,
+    /// flowlets, retransmissions, RTT, congestion control, and periodic goodput). Disabled
+    /// by default so a production run pays nothing for it. Mutually exclusive with
+    /// `--qlog-dir`; if both are set, `--qlog-dir` wins.
+    #[clap(long)]
+    qlog: Option<std::path::PathBuf>,
+
+    /// Directory to write one qlog-style JSON-lines stream per connection to, plus a
+    /// `global.qlog.jsonl` for events not scoped to a single connection. Lets qvis-style
+    /// tooling load one connection's events without filtering a shared stream; use `--qlog`
+    /// instead for a single combined stream. Each worker writes into its own `worker<N>`
+    /// subdirectory, created (along with this directory) if missing.
+    #[clap(long)]
+    qlog_dir: Option<std::path::PathBuf>,
 }
 
-#[tokio::main]
+/// Processed-packet/retransmission counts aggregated across every `--workers` shard, each of
+/// which otherwise only knows about its own `PEMI::stats`. Plain `AtomicU64`s rather than a
+/// `Mutex` since shards only ever add to their own counters at `print_interval`.
+struct AggregateStats {
+    pkts: AtomicU64,
+    retrans_pkts: AtomicU64,
+}
+
+impl AggregateStats {
+    fn new() -> Self {
+        AggregateStats {
+            pkts: AtomicU64::new(0),
+            retrans_pkts: AtomicU64::new(0),
+        }
+    }
+
+    /// Fold one shard's stats-since-last-print into the running total and print the total
+    /// across all shards so far.
+    fn add_and_print(&self, pkts: u64, retrans_pkts: u64) {
+        let total_pkts = self.pkts.fetch_add(pkts, Ordering::Relaxed) + pkts;
+        let total_retrans_pkts = self.retrans_pkts.fetch_add(retrans_pkts, Ordering::Relaxed) + retrans_pkts;
+        println!(
+            "-----aggregate stats: processed pkts: {}, retrans rate: {}",
+            total_pkts,
+            total_retrans_pkts as f64 / total_pkts as f64
+        );
+    }
+}
+
+#[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), String> {
     env_logger::builder()
         .format(|buf, record| writeln!(buf, "[{}] {}", record.level(), record.args()))
         .init();
     let args: Args = Args::parse();
+    let workers = args.workers.max(1);
 
-    let port = args.port;
-
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))
-        .map_err(|e| format!("error creating listening socket: {}", e))?;
+    println!("RETRANS_HELP: {}", RETRANS_HELP);
+    println!("listening on port {} with {} worker(s)", args.port, workers);
+
+    let aggregate = Arc::new(AggregateStats::new());
+    let handles: Vec<_> = (0..workers)
+        .map(|worker_id| {
+            let args = args.clone();
+            let aggregate = aggregate.clone();
+            tokio::spawn(async move { run_worker(worker_id, args, aggregate).await })
+        })
+        .collect();
+
+    for (worker_id, handle) in handles.into_iter().enumerate() {
+        handle
+            .await
+            .map_err(|e| format!("worker {} task panicked: {}", worker_id, e))??;
+    }
+    Ok(())
+}
 
-    // set socket options: SO_REUSEADDR, IP_TRANSPARENT, IP_RECVORIGDSTADDR
-    setsockopt(&socket, ReuseAddr, &true)
-        .map_err(|e| format!("error setting SO_REUSEADDR: {}", e))?;
-    setsockopt(&socket, IpTransparent, &true)
-        .map_err(|e| format!("error setting IP_TRANSPARENT: {}", e))?;
-    setsockopt(&socket, Ipv4OrigDstAddr, &true)
-        .map_err(|e| format!("error setting IP_RECVORIGDSTADDR: {}", e))?;
+/// Bind `args.port` with `SO_REUSEPORT` so the kernel hashes flows onto this shard
+/// consistently, then run PEMI's select/recv/process loop against a `PEMI` instance that
+/// belongs solely to this worker. One of these runs per `--workers` shard, each its own tokio
+/// task, so a multi-thread runtime can spread them across cores.
+async fn run_worker(worker_id: usize, args: Args, aggregate: Arc<AggregateStats>) -> Result<(), String> {
+    let socket =
+        bind_reuseport_socket(args.port).map_err(|e| format!("worker {}: {}", worker_id, e))?;
+    set_udp_gro(&socket).map_err(|e| format!("worker {}: {}", worker_id, e))?;
 
     // transfer to tokio socket
     socket
         .set_nonblocking(true)
-        .map_err(|e| format!("error setting non-blocking mode: {}", e))?;
+        .map_err(|e| format!("worker {}: error setting non-blocking mode: {}", worker_id, e))?;
     let socket = tokio::net::UdpSocket::from_std(socket)
-        .map_err(|e| format!("error converting to tokio socket: {}", e))?;
-
-    println!("RETRANS_HELP: {}", RETRANS_HELP);
-    println!("listening on port {}", port);
+        .map_err(|e| format!("worker {}: error converting to tokio socket: {}", worker_id, e))?;
 
     // init PEMI
     let mut pemi = PEMI::new();
-    pemi.set_factors(args.fl_inv_factor, args.fl_end_factor);
+    pemi.set_factors(args.fl_inv_factor, args.fl_end_factor, args.ack_freq_divisor);
     pemi.set_proxy_only(args.proxy_only);
+    pemi.set_retrans_cc_algo(args.cc.into());
+    pemi.set_conn_cc_algo(args.conn_cc.into());
+    if let Some(qlog_dir) = &args.qlog_dir {
+        // each shard keeps its own PEMI/connections, so each gets its own subdirectory rather
+        // than every worker racing to create the same per-connection files.
+        let qlog_dir = qlog_dir.join(format!("worker{}", worker_id));
+        std::fs::create_dir_all(&qlog_dir)
+            .map_err(|e| format!("error creating qlog dir {}: {}", qlog_dir.display(), e))?;
+        pemi = pemi.with_qlog(pemi::qlog::Qlog::new_per_connection(qlog_dir, time::Instant::now()));
+    } else if let Some(qlog_path) = &args.qlog {
+        // each shard keeps its own PEMI/connections, so each gets its own qlog file rather
+        // than every worker racing to append to one.
+        let qlog_path = qlog_path.with_extension(format!(
+            "worker{}.{}",
+            worker_id,
+            qlog_path.extension().and_then(|e| e.to_str()).unwrap_or("jsonl")
+        ));
+        let qlog_file = std::fs::File::create(&qlog_path)
+            .map_err(|e| format!("error creating qlog file {}: {}", qlog_path.display(), e))?;
+        pemi = pemi.with_qlog(pemi::qlog::Qlog::new(
+            Box::new(std::io::BufWriter::new(qlog_file)),
+            time::Instant::now(),
+        ));
+    }
 
-    let mut last_print_stats = 0;
+    let mut last_print_pkts = 0;
+    let mut last_print_retrans_pkts = 0;
+    let mut recv_slots: Vec<RecvSlot> = (0..RECV_BATCH).map(|_| RecvSlot::new()).collect();
     loop {
         #[cfg(any(feature = "cycles"))]
         let start_0 = unsafe { _rdtsc() };
 
-        let mut buf = [0u8; MAX_RECV_BUF].to_vec();
-
         let timeout = pemi.timeout();
 
         if timeout == Some(time::Duration::ZERO) {
@@ -117,8 +499,8 @@ async fn main() -> Result<(), String> {
             r = pemi.rtt_detector.wait_readable() => {
                 if r.is_ok() {
                     match pemi.rtt_detector.recv_response() {
-                            Ok(calibration_rtt_sample) => {
-                                pemi.rtt_calibration(calibration_rtt_sample);
+                            Ok((receiver_ip, calibration_rtt_sample)) => {
+                                pemi.rtt_calibration(receiver_ip, calibration_rtt_sample);
                                 #[cfg(any(feature = "cycles"))]
                                 count_cycles(0, start_0);
                                 continue; // process rtt response and continue
@@ -145,60 +527,23 @@ async fn main() -> Result<(), String> {
                 #[cfg(any(feature = "cycles"))]
                 let start_4 = unsafe { _rdtsc() }; // count cycles of extra wait due to false positive readable
 
-                // create iov
-                let mut iov = [IoSliceMut::new(&mut buf)];
-                let mut cmsgspace = nix::cmsg_space!([u8; 64]); // control message space
-
-                // recv message
-                let rmg: RecvMsg<'_, '_, SockaddrIn> = match recvmsg(
-                    socket.as_raw_fd(),
-                    &mut iov,
-                    Some(&mut cmsgspace),
-                    MsgFlags::empty(),
-                ) {
-                    Ok(rmg) => rmg,
-                    Err(e) => {
-                        if e == nix::errno::Errno::EAGAIN {
-                            // readable not mean necessarily recv will succeed
-                            #[cfg(any(feature = "cycles"))]
-                            count_cycles(4, start_4); // count into extra wait
-                            #[cfg(any(feature = "cycles"))]
-                            count_cycles(0, start_0);
-                            continue;
-                        } else {
-                            return Err(format!("error receiving message: {}", e));
-                        }
-                    }
+                // drain up to RECV_BATCH datagrams in one recvmmsg syscall instead of one
+                // recvmsg per wakeup; same completion-style batching compio-quic uses for
+                // QUIC servers.
+                let batch = match recv_batch(socket.as_raw_fd(), &mut recv_slots) {
+                    Ok(batch) => batch,
+                    Err(e) => return Err(format!("error receiving message: {}", e)),
                 };
-
-                let recv_ts = time::Instant::now();
-
-                // get the original destination address
-                let dstaddr = rmg
-                    .cmsgs()
-                    .map_err(|e| format!("error getting control messages: {}", e))?
-                    .find_map(|cmsg| match cmsg {
-                        nix::sys::socket::ControlMessageOwned::Ipv4OrigDstAddr(addr) => Some(addr),
-                        _ => None,
-                    })
-                    .ok_or("no original destination address in message")?;
-
-                let srcaddr = rmg.address.ok_or("no source address in message")?;
-                trace!(
-                    "Recv {} bytes, src: {}; dst: {}",
-                    rmg.bytes,
-                    pemi_io::print_addr(&srcaddr.as_ref()),
-                    pemi_io::print_addr(&dstaddr)
-                );
-
-                // parse the quiche packet and identify the connection. ref: RFC 9000 and 9312
-                // long header: try to parse, if is a QUIC initial packet, add new connection
-                // short header: find connection and process packet
-
-                let dstaddr = SockaddrIn::from(dstaddr);
-
-                let pkt_len = rmg.bytes;
-                buf.truncate(pkt_len);
+                let recv_ts = time::Instant::now(); // one reception timestamp for the whole batch
+
+                if batch.is_empty() {
+                    // readable() doesn't mean recvmmsg necessarily has anything queued
+                    #[cfg(any(feature = "cycles"))]
+                    count_cycles(4, start_4); // count into extra wait
+                    #[cfg(any(feature = "cycles"))]
+                    count_cycles(0, start_0);
+                    continue;
+                }
 
                 #[cfg(any(feature = "cycles"))]
                 count_cycles(1, start_1);
@@ -206,14 +551,25 @@ async fn main() -> Result<(), String> {
                 #[cfg(any(feature = "cycles"))]
                 let start_3 = unsafe { _rdtsc() }; // count cycles of pemi computing
 
-                pemi.process_packet(
-                    buf,
-                    recv_ts,
-                    pemi_io::Addr::from_nix_addr(srcaddr),
-                    pemi_io::Addr::from_nix_addr(dstaddr),
-                )?;
+                // parse the quiche packet and identify the connection. ref: RFC 9000 and 9312
+                // long header: try to parse, if is a QUIC initial packet, add new connection
+                // short header: find connection and process packet
+                for pkt in batch {
+                    trace!(
+                        "Recv {} bytes, src: {}; dst: {}",
+                        pkt.buf.len(),
+                        pemi_io::print_addr(&pkt.srcaddr),
+                        pemi_io::print_addr(&pkt.dstaddr)
+                    );
+                    pemi.process_packet(
+                        pkt.buf,
+                        recv_ts,
+                        pemi_io::Addr::from_nix_addr(pkt.srcaddr),
+                        pemi_io::Addr::from_nix_addr(pkt.dstaddr),
+                    )?;
+                }
                 while let Some(task) = pemi.pop_retrans_task() {
-                    debug!("process packet, retrans task: {}", task);
+                    debug!("process batch, retrans task: {}", task);
                     process_retrans_task(task, &mut pemi)?;
                 }
 
@@ -226,20 +582,29 @@ async fn main() -> Result<(), String> {
         {
             count_cycles(0, start_0);
             print_cycles_count_summary(pemi.pkts()); // count when finish processing a packet
+            pemi_io::print_send_syscall_summary(); // syscalls-per-datagram from the batched transparent-send path
         }
 
-        if pemi.pkts() - last_print_stats >= args.print_interval {
-            assert_eq!(pemi.pkts() - last_print_stats, args.print_interval);
-            last_print_stats = pemi.pkts();
+        if pemi.pkts() - last_print_pkts >= args.print_interval {
+            // A single event-loop iteration can process a whole recvmmsg batch (up to
+            // RECV_BATCH datagrams, more once GRO splitting is in play), so pemi.pkts() can
+            // jump past the interval rather than landing on it exactly; report the actual
+            // delta instead of assuming it's args.print_interval.
             pemi.print_stats();
+            aggregate.add_and_print(
+                pemi.pkts() - last_print_pkts,
+                pemi.retrans_pkts() - last_print_retrans_pkts,
+            );
+            last_print_pkts = pemi.pkts();
+            last_print_retrans_pkts = pemi.retrans_pkts();
         }
     }
 }
 
-fn process_retrans_task(mut task: retrans::Task, pemi: &mut PEMI) -> Result<(), String> {
+fn process_retrans_task(task: retrans::Task, pemi: &mut PEMI) -> Result<(), String> {
     if !RETRANS_HELP {
         return Ok(());
     }
-    pemi.process_retrans_task(&mut task)?;
+    pemi.process_retrans_task(task)?;
     Ok(())
 }
@@ -7,6 +7,19 @@ const MIN_RTT_WINDOW: time::Duration = time::Duration::from_secs(10);
 const MIN_STANDING_WINDOW: time::Duration = time::Duration::from_millis(10);
 const V_MAX: f64 = 32.0; // Maximum velocity
 
+/// Default 1/δ (δ=0.5). Copa's steady-state mode, used whenever the standing queue has been
+/// observed to empty recently.
+const DEFAULT_DELTA_RECIPROCAL: f64 = 2.0;
+/// Ceiling on 1/δ in competitive mode, so a permanently-full buffer can't push Copa into
+/// unbounded aggressiveness.
+const MAX_DELTA_RECIPROCAL: f64 = 10.0;
+/// dq below this is considered "queue emptied": RTTstanding caught up with RTTmin.
+const EMPTY_QUEUE_THRESHOLD: time::Duration = time::Duration::from_micros(200);
+/// Number of past RTTs considered when deciding whether to enter/leave competitive mode.
+const COMPETITIVE_WINDOW_RTTS: usize = 5;
+/// Multiplicative decrease applied to `cwnd` on a loss or ECN CE mark.
+const LOSS_CWND_BETA: f64 = 0.8;
+
 pub struct Copa {
     rtt_min_filter: Minmax<time::Duration>, // Minimum RTT seen in 10 seconds
     rtt_standing_filter: Minmax<time::Duration>, // Minimum RTT seen in smoothedRTT/2
@@ -19,6 +32,29 @@ pub struct Copa {
     cwnd_last_direction_change: f64,        // cwnd at the last direction change
     slow_start: bool,                       // slow start
     cwnd_used: UsedWindow,                  // Used window
+
+    /// Whether `dq = rtt_standing - rtt_min` has dropped below `EMPTY_QUEUE_THRESHOLD` at any
+    /// point during the RTT currently being observed. Folded into `queue_empty_window` once per
+    /// RTT, then reset.
+    queue_seen_empty_this_rtt: bool,
+    /// Per-RTT history (most recent last) of whether the queue was observed near-empty,
+    /// over the last `COMPETITIVE_WINDOW_RTTS` RTTs. Used to detect a competitor holding the
+    /// buffer full (Copa's "competitive mode").
+    queue_empty_window: VecDeque<bool>,
+    /// Whether Copa is currently in competitive mode (`delta_reciprocal` > default).
+    competitive_mode: bool,
+
+    /// Most recent RTT observed, used to size the debounce window in `on_loss`/`on_ecn_ce`.
+    last_rtt: time::Duration,
+    /// Start of the current loss/ECN backoff debounce window. `Some` for one RTT after a
+    /// congestion signal, so a burst of losses within that RTT collapses into a single
+    /// multiplicative decrease instead of compounding.
+    recovery_start: Option<time::Instant>,
+
+    /// Snapshot of the last `on_ack_send` decision, pulled by `Conn`/`PEMI` to emit a qlog
+    /// `cc_metrics_updated` event. `None` once taken, so a caller that doesn't opt into qlog
+    /// pays only the `Option` check.
+    last_metrics: Option<CopaMetrics>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -27,6 +63,32 @@ enum Direction {
     Down,
 }
 
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+        }
+    }
+}
+
+/// Snapshot of the values behind one `on_ack_send` decision, for the qlog
+/// `cc_metrics_updated` event (see `qlog::Event`). Mirrors the qlog
+/// `recovery:metrics_updated`/`recovery:congestion_state_updated` schema neqo uses.
+#[derive(Debug, Clone, Copy)]
+pub struct CopaMetrics {
+    pub cwnd: f64,
+    pub rtt_min: time::Duration,
+    pub rtt_standing: time::Duration,
+    pub dq: time::Duration,
+    pub lambda: f64,
+    pub lambda_t: f64,
+    pub v: f64,
+    pub direction: &'static str,
+    pub slow_start: bool,
+    pub pacing_rate: f64,
+}
+
 struct UsedWindow {
     packet_record: VecDeque<time::Instant>,
 }
@@ -55,7 +117,7 @@ impl Copa {
         Copa {
             rtt_min_filter: Minmax::new(time::Duration::MAX),
             rtt_standing_filter: Minmax::new(time::Duration::MAX),
-            delta_reciprocal: 2.0,
+            delta_reciprocal: DEFAULT_DELTA_RECIPROCAL,
             cwnd: 10.0,
             cwnd_change: now,
             v: 1.0,
@@ -64,12 +126,50 @@ impl Copa {
             cwnd_last_direction_change: 10.0,
             slow_start: true,
             cwnd_used: UsedWindow::new(),
+            queue_seen_empty_this_rtt: false,
+            queue_empty_window: VecDeque::with_capacity(COMPETITIVE_WINDOW_RTTS),
+            competitive_mode: false,
+            last_rtt: time::Duration::from_millis(1),
+            recovery_start: None,
+            last_metrics: None,
+        }
+    }
+
+    /// Take the snapshot of the last `on_ack_send` decision, if any arrived since the last
+    /// call. For feeding `qlog::Event::CcMetricsUpdated`.
+    pub fn take_metrics(&mut self) -> Option<CopaMetrics> {
+        self.last_metrics.take()
+    }
+
+    /// Multiplicative-decrease reaction to a loss or ECN CE mark: Copa is purely delay-driven
+    /// otherwise, so under tail-drop or an ECN-marking bottleneck it would never see a signal
+    /// at all. Debounced to at most once per RTT (`recovery_start`), matching the once-per-RTT
+    /// reaction window RFC 9002 congestion controllers use, so a burst of losses within the
+    /// same RTT collapses into a single reduction instead of compounding.
+    fn on_congestion_event(&mut self, now: time::Instant) {
+        if let Some(recovery_start) = self.recovery_start {
+            if now < recovery_start + self.last_rtt {
+                trace!("Copa:congestion event debounced, still in recovery");
+                return;
+            }
         }
+        self.recovery_start = Some(now);
+        self.cwnd = (self.cwnd * LOSS_CWND_BETA).max(10.0);
+        self.v = 1.0;
+        self.direction = Direction::Down;
+        self.cwnd_last_direction_change = self.cwnd;
+        self.direction_change = now;
+        if self.slow_start {
+            self.slow_start = false;
+            debug!("Copa:slow start end, congestion event");
+        }
+        debug!("Copa:congestion event, cwnd: {}", self.cwnd);
     }
 
     // Compute the recent sending rate and compare it with the target rate
     // Return: overspeed or not
     pub fn on_data_send(&mut self, now: time::Instant, client_rtt: time::Duration) -> bool {
+        self.last_rtt = client_rtt;
         let recent_sent = self.cwnd_used.on_data_send(client_rtt, now);
         let rtt_min = self
             .rtt_min_filter
@@ -89,6 +189,7 @@ impl Copa {
 
     // Update cwnd, v, direction
     pub fn on_ack_send(&mut self, client_rtt: time::Duration, now: time::Instant) {
+        self.last_rtt = client_rtt;
         // Minimum RTT seen in 10 seconds
         let rtt_min = self
             .rtt_min_filter
@@ -109,6 +210,7 @@ impl Copa {
         trace!("Copa:dq: {:?}", dq);
         trace!("Copa:λ_t: {}", lambda_t);
         trace!("Copa:λ: {}", lambda);
+        self.queue_seen_empty_this_rtt |= dq < EMPTY_QUEUE_THRESHOLD;
 
         // update cwnd
         if self.slow_start {
@@ -138,7 +240,53 @@ impl Copa {
             }
             self.cwnd_last_direction_change = self.cwnd;
             self.direction_change = now;
+
+            // Competitive mode: a loss-based competitor that keeps the buffer full prevents
+            // the standing queue from ever draining. If that holds for a full window of RTTs
+            // while Copa keeps getting pushed down, become more aggressive (1/δ += 1 per RTT);
+            // back off (halve toward the default) as soon as the queue empties again.
+            self.queue_empty_window.push_back(self.queue_seen_empty_this_rtt);
+            self.queue_seen_empty_this_rtt = false;
+            while self.queue_empty_window.len() > COMPETITIVE_WINDOW_RTTS {
+                self.queue_empty_window.pop_front();
+            }
+            if self.queue_empty_window.len() == COMPETITIVE_WINDOW_RTTS {
+                let queue_ever_emptied = self.queue_empty_window.iter().any(|&empty| empty);
+                if queue_ever_emptied {
+                    self.delta_reciprocal =
+                        (self.delta_reciprocal / 2.0).max(DEFAULT_DELTA_RECIPROCAL);
+                } else if self.direction == Direction::Down {
+                    self.delta_reciprocal =
+                        (self.delta_reciprocal + 1.0).min(MAX_DELTA_RECIPROCAL);
+                }
+                let was_competitive = self.competitive_mode;
+                self.competitive_mode = self.delta_reciprocal > DEFAULT_DELTA_RECIPROCAL;
+                if self.competitive_mode != was_competitive {
+                    debug!(
+                        "Copa:competitive mode {}, 1/δ: {}",
+                        if self.competitive_mode { "entered" } else { "exited" },
+                        self.delta_reciprocal
+                    );
+                }
+            }
+            trace!(
+                "Copa:competitive mode: {}, 1/δ: {}",
+                self.competitive_mode, self.delta_reciprocal
+            );
         }
+
+        self.last_metrics = Some(CopaMetrics {
+            cwnd: self.cwnd,
+            rtt_min,
+            rtt_standing,
+            dq,
+            lambda,
+            lambda_t,
+            v: self.v,
+            direction: self.direction.as_str(),
+            slow_start: self.slow_start,
+            pacing_rate: self.cwnd / rtt_standing.as_secs_f64(),
+        });
     }
 
     // Update cwnd in congestion avoidance phase
@@ -181,9 +329,292 @@ impl Copa {
         }
     }
 
+    /// React to `bytes_lost` worth of declared-lost packets with a multiplicative decrease.
+    /// `bytes_lost` isn't otherwise used: Copa backs off by a fixed factor regardless of how
+    /// much was lost in one go, the same way it reacts to a single CE mark.
+    pub fn on_loss(&mut self, _bytes_lost: usize, now: time::Instant) {
+        self.on_congestion_event(now);
+    }
+
+    /// React to an ECN CE mark the same way as a loss: Copa treats both as "the bottleneck
+    /// says slow down now" signals that its delay-based tracking alone would miss under
+    /// tail-drop or CE-marking queues.
+    ///
+    /// TODO: no caller yet. PEMI forwards over plain UDP sockets (see `pemi_io`) and never
+    /// reads the IP TOS byte, so there's nowhere to observe a real CE mark from today - wire
+    /// this up once PEMI reads ECN bits off the datagram (e.g. via `recvmsg` ancillary data).
+    pub fn on_ecn_ce(&mut self, now: time::Instant) {
+        self.on_congestion_event(now);
+    }
+
     // when calibration_rtt_sample >> measured RTT, reset rtt_min and rtt_standing to avoid the min RTT being erroneously small
     pub fn reset_rtt_filters(&mut self) {
         self.rtt_min_filter = Minmax::new(time::Duration::MAX);
         self.rtt_standing_filter = Minmax::new(time::Duration::MAX);
     }
 }
+
+impl CongestionControl for Copa {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    /// `Conn` feeds Copa one RTT sample at a time via the inherent `on_ack_send`; `update`
+    /// is the same operation under the shared trait name.
+    fn update(&mut self, now: time::Instant, rtt: time::Duration) {
+        self.on_ack_send(rtt, now);
+    }
+
+    fn on_loss(&mut self, bytes_lost: usize, now: time::Instant) {
+        self.on_loss(bytes_lost, now)
+    }
+
+    fn on_ecn_ce(&mut self, now: time::Instant) {
+        self.on_ecn_ce(now)
+    }
+
+    fn on_data_send(&mut self, now: time::Instant, rtt: time::Duration) -> bool {
+        self.on_data_send(now, rtt)
+    }
+
+    fn reset_rtt_filters(&mut self) {
+        self.reset_rtt_filters()
+    }
+
+    fn take_metrics(&mut self) -> Option<CopaMetrics> {
+        self.take_metrics()
+    }
+}
+
+/// Assumed packet size used by the retransmission-pacing controllers, in bytes.
+/// PEMI does not see the real path MTU, so a conservative QUIC-sized estimate is used.
+const RETRANS_MSS: f64 = 1350.0;
+
+/// Minimum congestion window, in bytes. Never shrink below a few packets,
+/// otherwise a single loss event could wedge retransmission pacing shut.
+const MIN_CWND: f64 = 2.0 * RETRANS_MSS;
+
+/// A pluggable congestion controller, capturing the observable surface shared by every
+/// algorithm PEMI uses: `Copa` (the delay-based controller historically driving the
+/// ack-reordering/overspeed heuristic for the original sender's flow, see `Conn`) and
+/// `NewReno`/`Cubic` (the loss-based windows pacing PEMI's *own* injected retransmissions,
+/// see `PEMI::process_retrans_task`, and now selectable for `Conn`'s overspeed decision too
+/// via `ConnCcAlgo`), so a single flat rate cap doesn't either starve or burst either role,
+/// and new algorithms have one seam to plug into.
+pub trait CongestionControl {
+    /// Current congestion window, in bytes.
+    fn cwnd(&self) -> f64;
+
+    /// Advance the window's growth to `now`, given the current RTT estimate.
+    /// Must be called before `cwnd()` is consulted for a send decision.
+    fn update(&mut self, now: time::Instant, rtt: time::Duration);
+
+    /// Record a loss event (as detected by PEMI's flowlet matcher), `bytes_lost` being the
+    /// total size of the packets declared lost in one go.
+    fn on_loss(&mut self, bytes_lost: usize, now: time::Instant);
+
+    /// Record an ECN CE (Congestion Experienced) mark. Treated the same as a loss by default,
+    /// since both are "the bottleneck says slow down now" signals; `Copa` debounces the two
+    /// together under one `recovery_start` window.
+    fn on_ecn_ce(&mut self, now: time::Instant) {
+        self.on_loss(0, now);
+    }
+
+    /// Compare the recent sending rate against the window's target rate and report whether
+    /// it's running ahead. `Copa` uses this directly to drive `Conn`'s reorder-ack heuristic;
+    /// purely window-based controllers like `NewReno`/`Cubic` don't make an overspeed call of
+    /// their own, so they default to `false`.
+    fn on_data_send(&mut self, _now: time::Instant, _rtt: time::Duration) -> bool {
+        false
+    }
+
+    /// Target pacing rate implied by the current window, in bytes/sec.
+    fn pacing_rate(&self, rtt: time::Duration) -> f64 {
+        self.cwnd() / rtt.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// Discard any RTT history the controller has accumulated, so the next sample starts
+    /// fresh. `Conn` calls this after a large RTT-calibration deviation, where a stale
+    /// `rtt_min` could otherwise look erroneously small forever. Only `Copa` keeps RTT
+    /// filters of its own; window-only controllers default to a no-op.
+    fn reset_rtt_filters(&mut self) {}
+
+    /// Take the snapshot of the last `update` decision, if any, for `qlog::Event::
+    /// CcMetricsUpdated`. Only `Copa` produces one today; window-only controllers default to
+    /// `None` so a caller that doesn't opt into qlog pays only the `Option` check.
+    fn take_metrics(&mut self) -> Option<CopaMetrics> {
+        None
+    }
+}
+
+/// NewReno-style window, grown as if one MSS were acked per RTT elapsed: slow start adds
+/// one MSS per RTT until `ssthresh`, then congestion avoidance adds `MSS * MSS / cwnd` per
+/// RTT; multiplicative decrease (beta = 0.5) on loss.
+#[derive(Debug)]
+pub struct NewReno {
+    cwnd: f64,
+    ssthresh: f64,
+    last_update: time::Instant,
+}
+
+impl NewReno {
+    pub fn new(now: time::Instant) -> Self {
+        NewReno {
+            cwnd: 10.0 * RETRANS_MSS,
+            ssthresh: f64::MAX,
+            last_update: now,
+        }
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    fn update(&mut self, now: time::Instant, rtt: time::Duration) {
+        if rtt.is_zero() {
+            return;
+        }
+        let t_delta = now.saturating_duration_since(self.last_update);
+        self.last_update = now;
+        let rtts_elapsed = t_delta.as_secs_f64() / rtt.as_secs_f64();
+        if self.cwnd < self.ssthresh {
+            // slow start: +1 MSS per RTT elapsed (cwnd += bytes_acked)
+            self.cwnd += RETRANS_MSS * rtts_elapsed;
+        } else {
+            // congestion avoidance: +MSS*MSS/cwnd per RTT elapsed (cwnd += mss*bytes_acked/cwnd)
+            self.cwnd += RETRANS_MSS * rtts_elapsed / (self.cwnd / RETRANS_MSS);
+        }
+    }
+
+    fn on_loss(&mut self, _bytes_lost: usize, _now: time::Instant) {
+        self.ssthresh = (self.cwnd * 0.5).max(MIN_CWND);
+        self.cwnd = self.ssthresh;
+        debug!("NewReno: loss, cwnd: {}", self.cwnd);
+    }
+}
+
+/// CUBIC congestion window, following the shape used by Linux/neqo/tquic:
+/// `w_cubic(t) = C * (t - k)^3 + w_max`, taking the max against a Reno estimate
+/// so CUBIC never falls slower than Reno in normal-RTT regimes.
+#[derive(Debug)]
+pub struct Cubic {
+    cwnd: f64,
+    w_max: f64,
+    beta: f64,
+    c: f64,
+    k: f64,
+    epoch_start: Option<time::Instant>,
+    w_est: f64, // Reno-estimate window, advanced by one fixed step on every `update` call
+}
+
+impl Cubic {
+    pub fn new(_now: time::Instant) -> Self {
+        Cubic {
+            cwnd: 10.0 * RETRANS_MSS,
+            w_max: 10.0 * RETRANS_MSS,
+            beta: 0.7,
+            c: 0.4,
+            k: 0.0,
+            epoch_start: None,
+            w_est: 10.0 * RETRANS_MSS,
+        }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    fn update(&mut self, now: time::Instant, rtt: time::Duration) {
+        if rtt.is_zero() {
+            return;
+        }
+        let epoch_start = *self.epoch_start.get_or_insert(now);
+        let t = now.saturating_duration_since(epoch_start).as_secs_f64();
+
+        // w_cubic(t) = C * (t - K)^3 + w_max
+        let w_cubic = self.c * (t - self.k).powi(3) + self.w_max;
+
+        // Reno-friendly estimate, advanced by one per-ACK-sized increment
+        // (MSS * MSS / w_est, scaled by 3(1-beta)/(1+beta)) per call; the time elapsed since
+        // the previous call is not taken into account.
+        self.w_est += RETRANS_MSS * (rtt.as_secs_f64() / rtt.as_secs_f64().max(1e-6))
+            * (RETRANS_MSS / self.w_est.max(RETRANS_MSS))
+            * (3.0 * (1.0 - self.beta) / (1.0 + self.beta));
+
+        self.cwnd = w_cubic.max(self.w_est).max(MIN_CWND);
+        trace!(
+            "Cubic: t={:.3}, k={:.3}, w_cubic={:.0}, w_est={:.0}, cwnd={:.0}",
+            t, self.k, w_cubic, self.w_est, self.cwnd
+        );
+    }
+
+    fn on_loss(&mut self, _bytes_lost: usize, now: time::Instant) {
+        self.w_max = self.cwnd;
+        self.cwnd = (self.cwnd * self.beta).max(MIN_CWND);
+        self.w_est = self.cwnd;
+        self.k = (self.w_max * (1.0 - self.beta) / self.c).cbrt();
+        self.epoch_start = Some(now);
+        debug!(
+            "Cubic: loss, w_max: {}, k: {}, cwnd: {}",
+            self.w_max, self.k, self.cwnd
+        );
+    }
+}
+
+/// Which window-growth curve `PEMI::process_retrans_task` should hand its per-destination
+/// `retrans_cc` entries, picked once for the whole run via `--cc`. `Copa` isn't offered here:
+/// it's delay-driven and meant for the main forwarding path's overspeed heuristic, not a
+/// from-scratch retransmission congestion window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetransCcAlgo {
+    Reno,
+    Cubic,
+}
+
+impl RetransCcAlgo {
+    /// Construct a fresh `CongestionControl` for a new retransmission destination.
+    pub fn new_cc(&self, now: time::Instant) -> Box<dyn CongestionControl> {
+        match self {
+            RetransCcAlgo::Reno => Box::new(NewReno::new(now)),
+            RetransCcAlgo::Cubic => Box::new(Cubic::new(now)),
+        }
+    }
+}
+
+impl Default for RetransCcAlgo {
+    fn default() -> Self {
+        RetransCcAlgo::Cubic
+    }
+}
+
+/// Which `CongestionControl` backend `Conn` drives the original flow's overspeed/pacing
+/// decision with, picked once per connection via `--conn-cc`. Unlike `RetransCcAlgo`, `Copa`
+/// is offered here since this is the forwarding-path role it was designed for; `Reno`/`Cubic`
+/// let PEMI instead match a loss-based bottleneck's own behavior when shepherding that flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnCcAlgo {
+    Copa,
+    Reno,
+    Cubic,
+}
+
+impl ConnCcAlgo {
+    /// Construct a fresh `CongestionControl` for a connection's forwarding path.
+    pub fn new_cc(&self, now: time::Instant) -> Box<dyn CongestionControl> {
+        match self {
+            ConnCcAlgo::Copa => Box::new(Copa::new(now)),
+            ConnCcAlgo::Reno => Box::new(NewReno::new(now)),
+            ConnCcAlgo::Cubic => Box::new(Cubic::new(now)),
+        }
+    }
+}
+
+impl Default for ConnCcAlgo {
+    fn default() -> Self {
+        ConnCcAlgo::Copa
+    }
+}
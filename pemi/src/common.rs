@@ -1,7 +1,24 @@
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum Error {
-    /// The provided buffer is too short. For use with the `octets` module.
-    BufferTooShort,
+/// What went wrong, without any information about where in a nested parse it happened. See
+/// `Error` for the wrapper that adds that context.
+///
+/// Not `Clone`/`Copy`/`PartialEq`/`Eq`: `Io` and `Utf8` wrap std error types that don't
+/// implement those either, since doing so would mean losing the underlying cause (e.g.
+/// `std::io::Error` can carry a raw OS error code that isn't meaningfully cloneable or
+/// comparable). Code that needs to branch on *which* error happened without caring about a
+/// wrapped cause should match on the variant instead of comparing `ErrorKind` values.
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// A read ran past the end of the buffer. `offset` is where in the packet the read started;
+    /// `needed` is how many more bytes were required than remained at that point. Produced by
+    /// `quic_parse`'s `octets` read wrappers, which attach this context since
+    /// `octets::BufferTooShortError` itself carries none.
+    Truncated { offset: usize, needed: usize },
+
+    /// A length-prefixed field (a connection ID length, or the Initial/Handshake/0-RTT `length`
+    /// field) named a size this parser won't accept: larger than QUIC allows, or larger than
+    /// what remains in the datagram. `offset` is where the size field itself was read from;
+    /// `size` is the invalid value it held.
+    InvalidSize { offset: usize, size: usize },
 
     /// This package is not in the usual QUIC format.
     MayNotQUIC,
@@ -10,25 +27,106 @@ pub enum Error {
     /// invalid state.
     InvalidState,
 
+    /// The packet matched the connection's `stateless_reset_token`: the peer has abandoned
+    /// this connection's state and the connection should be torn down.
+    StatelessReset,
+
+    /// The packet is malformed in a way that isn't a size problem covered by `InvalidSize`,
+    /// e.g. a short header packet where a length field is structurally disallowed. Since
+    /// header parsing runs on attacker-controlled bytes, this is returned rather than panicking.
+    InvalidPacket,
+
+    /// An underlying I/O failure, e.g. a socket read/write that isn't just `WouldBlock`
+    /// (callers still handle that case separately, the way `rtt_det::flush_pending` does).
+    /// No caller yet: today's socket handling in `pemi_io`/`rtt_det` returns `std::io::Error`
+    /// directly rather than wrapping it in this crate's `Error`.
+    Io(std::io::Error),
+
+    /// A string field (e.g. a decrypted SNI or ALPN value) wasn't valid UTF-8.
+    /// No caller yet: this crate doesn't decrypt or decode those fields today.
+    Utf8(std::str::Utf8Error),
+
     /// other errors
     Other(&'static str),
 }
 
+/// Where in a nested QUIC structure an `ErrorKind` was produced, innermost first: each entry is
+/// the (struct name, field name) being decoded when the error passed through that layer.
+#[derive(Debug)]
+struct ErrorData {
+    kind: ErrorKind,
+    context: Vec<(&'static str, &'static str)>,
+}
+
+/// A parsing/protocol error, carrying both `kind` (what went wrong) and a location stack (where
+/// it happened, across nested structures such as a header's fields or a frame's fields). Boxed
+/// so the common success path (`Result<T, Error>` with `T` typically small) isn't bloated by the
+/// `Vec` every `Err` doesn't usually need.
+#[derive(Debug)]
+pub struct Error(Box<ErrorData>);
+
+impl Error {
+    /// Returns what went wrong, without the location context.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.0.kind
+    }
+
+    /// Records that this error passed through `field_name` of `struct_name` while propagating
+    /// up a chain of nested parsers, e.g. `.map_err(|e| e.push_context("AckFrame",
+    /// "first_ack_range"))` at an `AckFrame` field's `?` site. Call this at every layer the
+    /// error passes through so `Display` can render the full nested path that broke.
+    pub fn push_context(mut self, struct_name: &'static str, field_name: &'static str) -> Self {
+        self.0.context.push((struct_name, field_name));
+        self
+    }
+}
+
+impl std::convert::From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error(Box::new(ErrorData {
+            kind,
+            context: Vec::new(),
+        }))
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        // `context` is pushed innermost-first as the error propagates outward, so reversing it
+        // renders the outermost struct first, e.g. "Frame.ack.AckFrame.first_ack_range:
+        // Truncated { .. }".
+        for (i, (struct_name, field_name)) in self.0.context.iter().rev().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{struct_name}.{field_name}")?;
+        }
+        if !self.0.context.is_empty() {
+            write!(f, ": ")?;
+        }
+        write!(f, "{:?}", self.0.kind)
     }
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match &self.0.kind {
+            ErrorKind::Io(e) => Some(e),
+            ErrorKind::Utf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::convert::From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        ErrorKind::Io(e).into()
     }
 }
 
-impl std::convert::From<octets::BufferTooShortError> for Error {
-    fn from(_err: octets::BufferTooShortError) -> Self {
-        Error::BufferTooShort
+impl std::convert::From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        ErrorKind::Utf8(e).into()
     }
 }
 
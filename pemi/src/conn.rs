@@ -4,7 +4,7 @@ use std::net::SocketAddr;
 use std::time;
 
 use crate::cc;
-use crate::common::Error;
+use crate::common::{Error, ErrorKind};
 use crate::pemi_io;
 use crate::queue;
 use crate::quic_parse;
@@ -21,8 +21,29 @@ const RTT_SMOOTHING_FACTOR: f64 = 1.0 / 8.0;
 const DELAY_kGranularity: time::Duration = time::Duration::from_millis(1);
 #[allow(non_upper_case_globals)]
 const DELAY_kTimeThreshold: f64 = 1.125; // 9/8 RTT
-#[allow(non_upper_case_globals)]
-const DELAY_kPacketThreshold: usize = 3; // 3 packets
+
+/// Assumed packet size, in bytes, used to turn `cc.cwnd()` (bytes) into a packet count for
+/// `ack_freq_pkt_threshold` before any packet has set `min_pkt_size`. Matches `cc::RETRANS_MSS`.
+const ASSUMED_PKT_SIZE: f64 = 1350.0;
+
+/// How long a DCID-matched migration candidate must keep showing up from the same address
+/// before `note_migration_candidate` commits the rebind. A single matching packet could be
+/// an off-path attacker replaying a captured DCID to hijack the flow's forwarding, or just
+/// one reordered packet racing a real path change; requiring the candidate to persist across
+/// this window is a lightweight stand-in for QUIC's PATH_CHALLENGE/PATH_RESPONSE validation,
+/// which PEMI can't perform itself since it never removes packet protection.
+const PATH_VALIDATION_GRACE: time::Duration = time::Duration::from_millis(200);
+
+/// Floor under the RTT-variance term of the connection-level probe timeout, matching RFC 9002's
+/// kGranularity. Mirrors `queue::PTO_GRANULARITY`, which governs flowlet-level retransmission
+/// probing - this one governs whole-connection liveness instead.
+const PTO_GRANULARITY: time::Duration = time::Duration::from_millis(1);
+
+/// How many consecutive probe timeouts (no packet seen in either direction for a full,
+/// exponentially-backed-off PTO interval) after which `on_timeout` declares the
+/// connection dead, so `PEMI::remove_idle_conns` can reclaim it without waiting out the full
+/// idle timeout.
+const MAX_PTO_COUNT: u32 = 6;
 
 /// Connection ID for PEMI connection management.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -53,6 +74,14 @@ impl std::fmt::Display for ConnId {
     }
 }
 
+impl ConnId {
+    /// A filesystem-safe rendering of this id, for naming per-connection qlog files
+    /// (`Display`'s "addr1 <-> addr2" has colons and spaces that aren't safe in filenames).
+    pub fn filename_safe(&self) -> String {
+        format!("{}_{}", self.addr1, self.addr2).replace([':', ' '], "_")
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum ConnState {
     /// After the client has sent the Initial packet
@@ -60,6 +89,9 @@ enum ConnState {
 
     /// After the server has sent the Handshake packet
     Handshaked,
+
+    /// After a stateless reset matching this connection's `stateless_reset_token` was seen.
+    Closed,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -90,16 +122,31 @@ pub struct Conn {
     client_addr: pemi_io::Addr,
     server_addr: pemi_io::Addr,
 
-    /// RTT from the client to PEMI.
+    /// Smoothed RTT from the client to PEMI (RFC 9002 section 5.3 `smoothed_rtt`).
     /// Unit: milliseconds.
     /// Init to 0, means has not been measured.
     client_rtt: time::Duration,
 
-    /// RTT from the server to PEMI.
+    /// Minimum client RTT observed so far (RFC 9002 `min_rtt`), alongside `client_rtt`.
+    /// `Duration::MAX` until the first sample, same sentinel `queue::RttEstimate` uses.
+    client_min_rtt: time::Duration,
+
+    /// EWMA of the mean deviation of client RTT samples from `client_rtt` (RFC 9002
+    /// `rttvar`), used by `rtt_calibration` to scale its deviation check to the connection's
+    /// own observed jitter instead of a fixed threshold.
+    client_rttvar: time::Duration,
+
+    /// Smoothed RTT from the server to PEMI (RFC 9002 section 5.3 `smoothed_rtt`).
     /// Unit: milliseconds.
     /// Init to 0, means has not been measured.
     server_rtt: time::Duration,
 
+    /// Minimum server RTT observed so far (RFC 9002 `min_rtt`), alongside `server_rtt`.
+    server_min_rtt: time::Duration,
+
+    /// EWMA of the mean deviation of server RTT samples from `server_rtt` (RFC 9002 `rttvar`).
+    server_rttvar: time::Duration,
+
     /// Queue of packets from the client to the server.
     to_server_queue: queue::PacketQueue,
 
@@ -116,14 +163,64 @@ pub struct Conn {
     server_bytes: usize,                   // bytes from server in this RTT period
     client_bytes: usize,                   // bytes from client in this RTT period
 
-    /// Congestion control
-    cc: cc::Copa,
+    /// Congestion control driving the overspeed/pacing decision for this connection's
+    /// forwarding path. Defaults to `Copa`; `PEMI::new_conn` may swap it for `Reno`/`Cubic`
+    /// via `set_cc_algo` (`--conn-cc`).
+    cc: Box<dyn cc::CongestionControl>,
     overspeed: bool,                         // whether the sending rate is overspeed
     overspeed_begin: Option<time::Instant>,  // when the overspeed begins
     delayed_ack_queue: VecDeque<DelayedACK>, // queue of delayed acks for reordering
 
+    /// Divisor `N` in `ack_freq_pkt_threshold`'s `max(2, cwnd_pkts / N)`, set via
+    /// `set_factors` (`--ack-freq-divisor`).
+    ack_freq_divisor: f64,
+
+    /// Pending qlog-worthy deltas since the last `take_*` pull. Mirrors `cc::Copa`'s
+    /// `last_metrics`/`take_metrics` pull style, so `Conn` stays oblivious to whether qlog is
+    /// actually wired up (`PEMI::with_qlog`) - callers just pull and drop the result if not.
+    pending_rtt_update: Option<(time::Duration, time::Duration)>,
+    pending_dominant_direction_change: Option<&'static str>,
+    pending_overspeed_change: Option<bool>,
+    pending_delayed_ack_flush: Option<usize>,
+    pending_rtt_reset: Option<time::Duration>,
+
     /// Last RTT calibration time
     last_rtt_calibration: time::Instant,
+
+    /// The client's randomly-chosen Initial DCID. Used to recognize this connection by
+    /// DCID even if the client's address changes (NAT rebinding / migration).
+    client_initial_dcid: Vec<u8>,
+
+    /// The connection ID the server chose for the client to use afterwards (the server's
+    /// SCID in its Handshake packet), which becomes the DCID of later client short-header
+    /// packets. `None` until the Handshake packet is seen.
+    server_chosen_cid: Option<Vec<u8>>,
+
+    /// The effective idle timeout for this connection: `min(client, server)` of the
+    /// negotiated `max_idle_timeout` transport parameters, falling back to `IDLE_TIMEOUT`
+    /// when a peer's value hasn't been folded in (see `negotiate_idle_timeout`).
+    idle_timeout: time::Duration,
+
+    /// The `stateless_reset_token` the server advertised in its transport parameters.
+    /// `None` until folded in by `set_stateless_reset_token`. Checked against the trailing
+    /// bytes of otherwise-unparseable short-header packets in `process_quic_packet`.
+    stateless_reset_token: Option<[u8; 16]>,
+
+    /// A client address PEMI has seen arrive under this connection's DCID but hasn't yet
+    /// rebound to, paired with when it was first seen. `None` unless a migration/NAT-rebind
+    /// candidate is being validated (see `note_migration_candidate`). Treated as an alias for
+    /// `client_addr` by `is_from_client` while pending, so packets from the candidate keep
+    /// flowing through the connection's existing state instead of being misread as a reply.
+    pending_client_addr: Option<(SocketAddr, time::Instant)>,
+
+    /// Consecutive probe timeouts fired by `on_timeout` with no packet seen in either
+    /// direction since `last_access`, doubling the effective PTO interval each time (RFC 9002
+    /// `pto_count`). Reset to 0 on any packet (see `update_access_time`).
+    pto_count: u32,
+
+    /// Set once `pto_count` reaches `MAX_PTO_COUNT`: the connection is considered dead and
+    /// `PEMI::remove_idle_conns` reclaims it immediately instead of waiting out `idle_timeout`.
+    dead: bool,
 }
 
 impl Conn {
@@ -135,7 +232,11 @@ impl Conn {
             client_addr: pemi_io::Addr::from_std_addr(src), // for the first packet, the src is the client
             server_addr: pemi_io::Addr::from_std_addr(dst), // and the dst is the server
             client_rtt: time::Duration::from_secs(0),
+            client_min_rtt: time::Duration::MAX,
+            client_rttvar: time::Duration::ZERO,
             server_rtt: time::Duration::from_secs(0),
+            server_min_rtt: time::Duration::MAX,
+            server_rttvar: time::Duration::ZERO,
             to_server_queue: queue::PacketQueue::new(),
             to_server_pkt_num: 0,
             to_client_queue: queue::PacketQueue::new(),
@@ -144,19 +245,127 @@ impl Conn {
             last_dominant_check: now,
             server_bytes: 0,
             client_bytes: 0,
-            cc: cc::Copa::new(now),
+            cc: Box::new(cc::Copa::new(now)),
             overspeed: false,
             overspeed_begin: None,
             delayed_ack_queue: VecDeque::new(),
+            ack_freq_divisor: 4.0,
+            pending_rtt_update: None,
+            pending_dominant_direction_change: None,
+            pending_overspeed_change: None,
+            pending_delayed_ack_flush: None,
+            pending_rtt_reset: None,
             last_rtt_calibration: now,
+            client_initial_dcid: Vec::new(),
+            server_chosen_cid: None,
+            idle_timeout: IDLE_TIMEOUT,
+            stateless_reset_token: None,
+            pending_client_addr: None,
+            pto_count: 0,
+            dead: false,
+        }
+    }
+
+    /// Fold a peer's advertised `max_idle_timeout` transport parameter into the connection's
+    /// effective idle timeout, which is the minimum across both peers (RFC 9000 section
+    /// 10.1). A value of `Duration::ZERO` means the peer disabled the idle timeout and is
+    /// ignored, matching the QUIC spec's "0 means disabled" rule.
+    ///
+    /// **Not called in production.** Both peers' `max_idle_timeout` are exchanged as a TLS
+    /// `quic_transport_parameters` extension: the client's in its ClientHello (Initial-level
+    /// CRYPTO, whose keys RFC 9001 section 5.2 derives publicly from the DCID - readable
+    /// without completing the handshake) and the server's in its EncryptedExtensions
+    /// (Handshake-level CRYPTO, keyed by the ECDHE shared secret - not readable without
+    /// completing it). PEMI removes protection from neither today (see
+    /// `first_quic_packet`/`process_quic_packet`), so `quic_parse::parse_max_idle_timeout`
+    /// has nowhere to read a transport-parameters block from; only the unit tests below call
+    /// this. Wiring it up for real is future work gated on at least Initial-level
+    /// decryption, not a missing call site.
+    pub fn negotiate_idle_timeout(&mut self, peer_max_idle_timeout: time::Duration) {
+        if peer_max_idle_timeout.is_zero() {
+            return;
+        }
+        self.idle_timeout = self.idle_timeout.min(peer_max_idle_timeout);
+        debug!("negotiated idle timeout: {:?}", self.idle_timeout);
+    }
+
+    /// Record the server's advertised `stateless_reset_token`, later checked against
+    /// otherwise-unparseable short-header packets by `is_stateless_reset`.
+    ///
+    /// **Not called in production**, for the same reason as `negotiate_idle_timeout`: the
+    /// `stateless_reset_token` transport parameter is carried in the server's
+    /// EncryptedExtensions, sent in Handshake-level CRYPTO data that's protected with keys
+    /// derived from the TLS ECDHE shared secret - not recoverable by a passive relay like
+    /// PEMI, which removes protection from neither Initial nor Handshake packets (see
+    /// `first_quic_packet`/`process_quic_packet`). Until PEMI actually terminates or
+    /// otherwise gains the handshake secrets, `is_stateless_reset` below always returns
+    /// `false` outside the unit tests that call this directly. Treat eviction-on-reset as
+    /// unimplemented in production, not merely unwired.
+    pub fn set_stateless_reset_token(&mut self, token: [u8; 16]) {
+        self.stateless_reset_token = Some(token);
+    }
+
+    /// Whether `buf` is a stateless reset for this connection: its trailing 16 bytes match
+    /// the token the server advertised. Per RFC 9000 section 10.3, a stateless reset is at
+    /// least 21 bytes (1 header byte + at least 4 bytes + the 16-byte token), so shorter
+    /// packets are never considered a match.
+    fn is_stateless_reset(&self, buf: &[u8]) -> bool {
+        match self.stateless_reset_token {
+            Some(token) => buf.len() >= 21 && buf[buf.len() - 16..] == token,
+            None => false,
         }
     }
 
-    pub fn set_factors(&mut self, flowlet_interval_factor: f64, flowlet_end_factor: f64) {
+    /// Mark the connection closed after a stateless reset is detected. The caller is
+    /// responsible for evicting it from `PEMI`'s tracking.
+    fn set_closed(&mut self) {
+        self.state = ConnState::Closed;
+    }
+
+    /// Rebind this connection to a new client address, e.g. after NAT rebinding or QUIC
+    /// connection migration was recognized by a DCID match on a packet from a new 4-tuple.
+    /// The server side is assumed unchanged; PEMI does not yet support server-side migration.
+    pub fn rebind(&mut self, now: time::Instant, new_client_addr: SocketAddr) {
+        info!(
+            "conn {} rebinding client address {} -> {}",
+            ConnId::new(self.client_addr.std_addr, self.server_addr.std_addr),
+            self.client_addr.std_addr,
+            new_client_addr
+        );
+        self.client_addr = pemi_io::Addr::from_std_addr(new_client_addr);
+        self.pending_client_addr = None;
+        self.last_access = now;
+    }
+
+    /// The client's randomly-chosen Initial DCID, used to recognize the connection across
+    /// address changes before the handshake completes.
+    pub fn client_initial_dcid(&self) -> &[u8] {
+        &self.client_initial_dcid
+    }
+
+    /// The connection ID the server chose for the client to use (its Handshake SCID), which
+    /// becomes the DCID of later client short-header packets. `None` before the Handshake.
+    pub fn server_chosen_cid(&self) -> Option<&[u8]> {
+        self.server_chosen_cid.as_deref()
+    }
+
+    pub fn set_factors(
+        &mut self,
+        flowlet_interval_factor: f64,
+        flowlet_end_factor: f64,
+        ack_freq_divisor: f64,
+    ) {
         self.to_server_queue
             .set_factors(flowlet_interval_factor, flowlet_end_factor);
         self.to_client_queue
             .set_factors(flowlet_interval_factor, flowlet_end_factor);
+        self.ack_freq_divisor = ack_freq_divisor;
+    }
+
+    /// Swap this connection's congestion-control backend (`--conn-cc`). Applied once, right
+    /// after construction, by `PEMI::new_conn` - mirrors `set_factors`.
+    pub fn set_cc_algo(&mut self, algo: cc::ConnCcAlgo, now: time::Instant) {
+        self.cc = algo.new_cc(now);
     }
 
     /// Return elapsed time since the connection is created.
@@ -176,11 +385,11 @@ impl Conn {
         let hdr = quic_parse::Header::from_bytes(&mut b, 0)?;
         debug!("parsed pkt header(first): {:?}", hdr);
         if hdr.ty != quic_parse::Type::Initial {
-            return Err(Error::MayNotQUIC);
+            return Err(ErrorKind::MayNotQUIC.into());
         }
 
         // skip the payload
-        b.skip(hdr.length)?;
+        quic_parse::skip(&mut b, hdr.length)?;
 
         // check if the left is UDP padding
         let read = if b.cap() > 0 && quic_parse::Header::is_udp_padding(&mut b)? {
@@ -189,7 +398,9 @@ impl Conn {
         } else {
             b.off()
         };
-        Ok((Conn::new(*now, *src, *dst), read))
+        let mut conn = Conn::new(*now, *src, *dst);
+        conn.client_initial_dcid = hdr.dcid.as_ref().to_vec();
+        Ok((conn, read))
     }
 
     /// For processing coalesced QUIC packets.
@@ -208,13 +419,24 @@ impl Conn {
             debug!("parsed pkt header(following): {:?}", hdr);
 
             if hdr.ty == quic_parse::Type::Handshake {
-                assert!(!self.is_from_client(src));
+                // A Handshake packet is only ever expected from the server (the client's
+                // Initial DCID it carries is cleartext, so anyone who observed it can forge
+                // one); `src` here may also just be an unvalidated migration candidate (see
+                // `is_from_client`), so this can't assume a well-behaved peer. Reject rather
+                // than `assert!`: the attacker controls `src`, so a failed check here must be
+                // a recoverable per-packet error, not a process-ending panic.
+                if self.is_from_client(src) {
+                    return Err(ErrorKind::InvalidPacket.into());
+                }
                 self.set_handshaked();
+                // The server's SCID here becomes the DCID of the client's later
+                // short-header packets; record it so PEMI can recognize migration.
+                self.server_chosen_cid = Some(hdr.scid.as_ref().to_vec());
                 info!("conn handshaked");
             }
 
             // skip the payload
-            b.skip(hdr.length)?;
+            quic_parse::skip(&mut b, hdr.length)?;
 
             // check if the left is UDP padding
             if b.cap() > 0 && quic_parse::Header::is_udp_padding(&mut b)? {
@@ -223,6 +445,11 @@ impl Conn {
             } else {
                 b.off()
             }
+        } else if self.is_stateless_reset(buf) {
+            // The server has abandoned this connection's state; don't keep "helping" it.
+            self.set_closed();
+            info!("conn stateless reset detected");
+            return Err(ErrorKind::StatelessReset.into());
         } else {
             // parse the packet (may be the short header packet)
             // The connection is handshaked. No need to parse the packet.
@@ -265,6 +492,7 @@ impl Conn {
                 "check dominant direction, interval: {:?}",
                 recv_ts.duration_since(self.last_dominant_check)
             );
+            let prev_direction = self.dominant_direction;
             if self.client_bytes * 2 < self.server_bytes {
                 // to client
                 self.dominant_direction = DominantDirection::ToClient;
@@ -272,6 +500,13 @@ impl Conn {
                 // to server
                 self.dominant_direction = DominantDirection::ToServer;
             }
+            if self.dominant_direction != prev_direction {
+                self.pending_dominant_direction_change = Some(match self.dominant_direction {
+                    DominantDirection::ToClient => "to_client",
+                    DominantDirection::ToServer => "to_server",
+                    DominantDirection::None => "none",
+                });
+            }
             debug!(
                 "dominant direction: {:?}, client_bytes: {}, server_bytes: {}",
                 self.dominant_direction, self.client_bytes, self.server_bytes
@@ -285,28 +520,105 @@ impl Conn {
 
     pub fn is_from_client(&self, src: &SocketAddr) -> bool {
         *src == self.client_addr.std_addr
+            || self.pending_client_addr.is_some_and(|(addr, _)| addr == *src)
+    }
+
+    /// Record a packet arriving under this connection's DCID from `candidate`, a 4-tuple this
+    /// connection isn't currently bound to. Returns `true` once `candidate` has kept showing
+    /// up for at least `PATH_VALIDATION_GRACE` and the caller should commit the rebind (via
+    /// `rebind`); otherwise the candidate is noted (or re-noted, restarting the window) and
+    /// the connection keeps serving under its current address in the meantime.
+    ///
+    /// Any sighting of a *different* candidate than the one currently pending restarts the
+    /// grace window from scratch, so an attacker can't bypass validation by alternating
+    /// between several spoofed addresses.
+    pub fn note_migration_candidate(&mut self, candidate: SocketAddr, now: time::Instant) -> bool {
+        match self.pending_client_addr {
+            Some((addr, since)) if addr == candidate => {
+                if now.duration_since(since) >= PATH_VALIDATION_GRACE {
+                    return true;
+                }
+                false
+            }
+            _ => {
+                debug!("conn: new migration candidate {candidate}, starting validation grace period");
+                self.pending_client_addr = Some((candidate, now));
+                false
+            }
+        }
     }
 
-    // smoothed_rtt = 7/8 * smoothed_rtt + 1/8 * sample_rtt
-    fn update_client_rtt(&mut self, value: time::Duration, now: time::Instant) {
+    /// Fold a new client RTT sample into the RFC 9002 section 5.3 estimator: first sample
+    /// seeds `client_rtt`/`client_rttvar`/`client_min_rtt` directly; later samples are first
+    /// corrected for `ack_delay` (dropped if it would push the sample below `client_min_rtt`),
+    /// then folded into `client_rttvar`/`client_rtt` via the usual 3/4-1/4 and 7/8-1/8 EWMAs.
+    /// `ack_delay` is always `Duration::ZERO` today - PEMI never removes packet protection, so
+    /// there's nowhere to read a real QUIC ACK frame's Ack Delay field from - but callers pass
+    /// it through so that seam is ready once one does.
+    fn update_client_rtt(&mut self, value: time::Duration, ack_delay: time::Duration, now: time::Instant) {
         // assert!(value >= RTT_GRANULARITY);
         if self.client_rtt.is_zero() {
             // the first client RTT
             self.client_rtt = value;
+            self.client_min_rtt = value;
+            self.client_rttvar = value / 2;
             debug!("initial client RTT: {:?}", self.client_rtt);
         } else {
+            let adjusted_rtt = if value >= self.client_min_rtt + ack_delay {
+                value - ack_delay
+            } else {
+                value
+            };
+            let deviation = if self.client_rtt > adjusted_rtt {
+                self.client_rtt - adjusted_rtt
+            } else {
+                adjusted_rtt - self.client_rtt
+            };
+            self.client_rttvar = self.client_rttvar.mul_f64(0.75) + deviation.mul_f64(0.25);
             self.client_rtt = self.client_rtt.mul_f64(1.0 - RTT_SMOOTHING_FACTOR)
-                + value.mul_f64(RTT_SMOOTHING_FACTOR);
+                + adjusted_rtt.mul_f64(RTT_SMOOTHING_FACTOR);
+            self.client_min_rtt = self.client_min_rtt.min(value);
             debug!("updated client RTT: {:?}", self.client_rtt);
         }
-        self.cc.on_ack_send(self.client_rtt, now);
+        self.pending_rtt_update = Some((self.client_rtt, self.server_rtt));
+        self.cc.update(now, self.client_rtt);
     }
 
-    // smoothed_rtt = 7/8 * smoothed_rtt + 1/8 * sample_rtt
-    // TODO: change as same as update_client_rtt
-    fn update_server_rtt(&mut self, value: time::Duration) {
-        self.server_rtt = value;
+    /// Fold a new server RTT sample into the RFC 9002 section 5.3 estimator. Mirrors
+    /// `update_client_rtt`; `ack_delay` is always `Duration::ZERO` for the same reason.
+    fn update_server_rtt(&mut self, value: time::Duration, ack_delay: time::Duration) {
+        if self.server_rtt.is_zero() {
+            // the first server RTT
+            self.server_rtt = value;
+            self.server_min_rtt = value;
+            self.server_rttvar = value / 2;
+        } else {
+            let adjusted_rtt = if value >= self.server_min_rtt + ack_delay {
+                value - ack_delay
+            } else {
+                value
+            };
+            let deviation = if self.server_rtt > adjusted_rtt {
+                self.server_rtt - adjusted_rtt
+            } else {
+                adjusted_rtt - self.server_rtt
+            };
+            self.server_rttvar = self.server_rttvar.mul_f64(0.75) + deviation.mul_f64(0.25);
+            self.server_rtt = self.server_rtt.mul_f64(1.0 - RTT_SMOOTHING_FACTOR)
+                + adjusted_rtt.mul_f64(RTT_SMOOTHING_FACTOR);
+            self.server_min_rtt = self.server_min_rtt.min(value);
+        }
         debug!("server RTT: {:?}", self.server_rtt);
+        self.pending_rtt_update = Some((self.client_rtt, self.server_rtt));
+    }
+
+    /// Whether `sample` deviates from the tracked client RTT enough to suggest `client_min_rtt`
+    /// may be erroneously low (RFC 9002-style `smoothed_rtt + 4*rttvar` bound). Used by
+    /// `rtt_calibration` to decide whether an external (mimic spin-bit) RTT sample should reset
+    /// the client-side min-RTT tracking, separately from whether it should reset PEMI's flowlet
+    /// state (that's judged independently by `to_client_queue.reset_due_to_rtt_deviation`).
+    fn client_rtt_deviates(&self, sample: time::Duration) -> bool {
+        !self.client_rtt.is_zero() && sample > self.client_rtt + self.client_rttvar * 4
     }
 
     /// This used for case where we disable the PEMI enhancement, only forwarding the UDP packet.
@@ -334,26 +646,29 @@ impl Conn {
     }
 
     /// Process the UDP packet.
-    /// Return: whether the new protected flowlet(to client data) is created.
+    /// Return: (whether a new protected flowlet (to client data) was created, whether a
+    /// protected flowlet was marked complete and removed from the queue).
     pub fn process_udp_packet(
         &mut self,
         recv_ts: time::Instant,
         src: &pemi_io::Addr,
         _dst: &pemi_io::Addr,
         buf: Vec<u8>,
-    ) -> bool {
+    ) -> (bool, bool) {
         let from;
         let mut new_flowlet = false;
+        let mut flowlet_ended = false;
         // measure initial RTT of both sides
         if self.is_from_client(&src.std_addr) {
             from = "client";
             // process client packet
             if self.client_rtt.is_zero() && !self.server_rtt.is_zero() {
                 // measure the first client RTT(PEMI<->client RTT)
-                self.update_client_rtt(
-                    recv_ts.duration_since(self.to_client_queue.oldest_ts().unwrap()), // when server_rtt > 0(measured), the server queue must have packets(the 1st is the Handshake/Retry packet)
-                    recv_ts,
-                );
+                let sample = recv_ts.duration_since(self.to_client_queue.oldest_ts().unwrap()); // when server_rtt > 0(measured), the server queue must have packets(the 1st is the Handshake/Retry packet)
+                self.update_client_rtt(sample, time::Duration::ZERO, recv_ts);
+                // seed the queue's own RTT estimate so its flowlet matching/timeout math
+                // doesn't start from a bare Duration::ZERO
+                self.to_client_queue.seed_rtt(sample, recv_ts);
             }
         } else {
             from = "server";
@@ -362,6 +677,7 @@ impl Conn {
                 // measure the first server RTT(PEMI<->server RTT)
                 self.update_server_rtt(
                     recv_ts.duration_since(self.to_server_queue.oldest_ts().unwrap()), // when received the first server packet, the client queue must have packets(the 1st is the Initial packet)
+                    time::Duration::ZERO,
                 );
             }
         }
@@ -387,14 +703,19 @@ impl Conn {
 
             // protect pkts to client: pkts to client expect reply from client
             self.to_server_pkt_num += 1; // for recording replies' pkt number in flowlet
-            let rtt_samples =
-                self.to_client_queue
-                    .check_reply(recv_ts, self.to_server_pkt_num, self.client_rtt);
+            let rtt_samples = self
+                .to_client_queue
+                .check_reply(recv_ts, self.to_server_pkt_num);
             if let Some(samples) = rtt_samples {
+                flowlet_ended = !samples.is_empty();
                 for rtt_sample in samples {
-                    self.update_client_rtt(rtt_sample, recv_ts);
+                    self.update_client_rtt(rtt_sample, time::Duration::ZERO, recv_ts);
                 }
             }
+            let lossed_bytes = self.to_client_queue.take_lossed_bytes();
+            if lossed_bytes > 0 {
+                self.cc.on_loss(lossed_bytes, recv_ts);
+            }
             debug!("process client reply: {}", self.to_server_pkt_num);
         } else {
             // from server
@@ -404,9 +725,11 @@ impl Conn {
                 if self.overspeed {
                     if self.overspeed_begin.is_none() {
                         self.overspeed_begin = Some(recv_ts);
+                        self.pending_overspeed_change = Some(true);
                     }
                 } else if self.overspeed_begin.is_some() {
                     self.overspeed_begin = None;
+                    self.pending_overspeed_change = Some(false);
                 }
             }
             // add new packet to the to_client queue
@@ -418,7 +741,7 @@ impl Conn {
             debug!("to client queue: {}", self.to_client_queue);
         }
         self.check_delayed_acks(recv_ts);
-        new_flowlet
+        (new_flowlet, flowlet_ended)
     }
 
     pub fn rtt_calibration(&mut self, calibration_rtt_sample: time::Duration) {
@@ -432,31 +755,23 @@ impl Conn {
                 now_ts, calibration_rtt_sample
             );
 
-            // If the difference is large, reset PEMI: delete all flowlets that have found a reply; only focus on flowlets that have no reply yet
-            // calculate the RTT error
-            let rtt_error = if calibration_rtt_sample >= self.client_rtt {
-                calibration_rtt_sample - self.client_rtt
-            } else {
-                self.client_rtt - calibration_rtt_sample
-            };
-
-            let allowable_error = self
+            // If the calibration sample deviates from the to-client queue's own RTT estimate
+            // (RFC 9002-style smoothed_rtt + 4*rttvar bound), reset PEMI: delete all flowlets
+            // that have found a reply; only focus on flowlets that have no reply yet.
+            if self
                 .to_client_queue
-                .flowlet_timeout(&calibration_rtt_sample)
-                .mul_f64(self.to_client_queue.flowlet_end_factor); // small error is recoverable, so no need to reset
-
-            debug!("RTT Error: {:?}, allowed: {:?}", rtt_error, allowable_error);
-
-            if rtt_error > allowable_error {
+                .reset_due_to_rtt_deviation(calibration_rtt_sample)
+            {
                 info!(
                     "Large RTT deviation detected: calibration RTT {:?} vs current client RTT {:?}. Resetting PEMI.",
                     calibration_rtt_sample,
                     self.client_rtt
                 );
-                self.to_client_queue.reset_due_to_rtt_deviation();
-                if self.client_rtt < calibration_rtt_sample {
+                if self.client_rtt_deviates(calibration_rtt_sample) {
                     // reset rtt filters, since the min RTT may be erroneously small
                     self.cc.reset_rtt_filters();
+                    self.client_min_rtt = calibration_rtt_sample;
+                    self.pending_rtt_reset = Some(self.client_min_rtt);
                 }
                 self.client_rtt = calibration_rtt_sample;
             }
@@ -479,6 +794,42 @@ impl Conn {
         }
     }
 
+    /// Take the snapshot of the last congestion-control decision, if an RTT sample was fed to
+    /// `cc` since the last call. For feeding `qlog::Event::CcMetricsUpdated`.
+    pub fn take_cc_metrics(&mut self) -> Option<cc::CopaMetrics> {
+        self.cc.take_metrics()
+    }
+
+    /// Take the (client_rtt, server_rtt) smoothed estimate if either was updated since the
+    /// last call. For feeding `qlog::Event::RttUpdated`.
+    pub fn take_rtt_update(&mut self) -> Option<(time::Duration, time::Duration)> {
+        self.pending_rtt_update.take()
+    }
+
+    /// Take the dominant-direction transition, if `measure_dominant_direction` flipped it since
+    /// the last call. For feeding `qlog::Event::DominantDirectionChanged`.
+    pub fn take_dominant_direction_change(&mut self) -> Option<&'static str> {
+        self.pending_dominant_direction_change.take()
+    }
+
+    /// Take the overspeed transition (`Some(true)` = began, `Some(false)` = ended) since the
+    /// last call. For feeding `qlog::Event::OverspeedBegin`/`OverspeedEnd`.
+    pub fn take_overspeed_change(&mut self) -> Option<bool> {
+        self.pending_overspeed_change.take()
+    }
+
+    /// Take the number of delayed ACKs `check_delayed_acks` released, if it flushed any since
+    /// the last call. For feeding `qlog::Event::DelayedAckFlushed`.
+    pub fn take_delayed_ack_flush(&mut self) -> Option<usize> {
+        self.pending_delayed_ack_flush.take()
+    }
+
+    /// Take the new `client_min_rtt`, if `rtt_calibration` reset it since the last call. For
+    /// feeding `qlog::Event::RttCalibrationReset`.
+    pub fn take_rtt_reset(&mut self) -> Option<time::Duration> {
+        self.pending_rtt_reset.take()
+    }
+
     fn is_handshaked(&self) -> bool {
         self.state == ConnState::Handshaked
     }
@@ -489,28 +840,75 @@ impl Conn {
 
     fn update_access_time(&mut self, now: time::Instant) {
         self.last_access = now;
+        // a packet arrived in either direction: the connection is making progress, so the
+        // probe-timeout backoff built up while it was quiet no longer applies.
+        self.pto_count = 0;
+    }
+
+    /// RFC 9002-style probe timeout anchored on the client RTT estimate: `smoothed_rtt +
+    /// max(4*rttvar, granularity) + max_ack_delay`, doubled per consecutive `pto_count`
+    /// backoff. `max_ack_delay` is `Duration::ZERO` for the same reason `update_client_rtt`'s
+    /// `ack_delay` is: PEMI never removes packet protection, so there's no real ACK Delay
+    /// field to read.
+    fn pto_timeout(&self) -> time::Duration {
+        let pto_base = self.client_rtt + self.client_rttvar.mul_f64(4.0).max(PTO_GRANULARITY);
+        pto_base * 2u32.pow(self.pto_count.min(MAX_PTO_COUNT))
     }
 
-    /// Get the timeout of the connection: the time for the loss detection. This now setted by the to client queue.
+    /// Get the timeout of the connection: the min of the to-client queue's own loss-detection
+    /// timer and this connection's probe timeout, which fires when no packet has been seen in
+    /// either direction for a full (backed-off) PTO interval.
     pub fn timeout(&self, now: time::Instant) -> Option<time::Duration> {
+        let queue_timeout = self.to_client_queue.timeout(now);
         if self.client_rtt.is_zero() {
-            // the client RTT has not been measured, can't set the timeout
-            return None;
-        } else {
-            return self.to_client_queue.timeout(now, self.client_rtt);
-        }
+            // no RTT sample yet, can't size a PTO
+            return queue_timeout;
 
-        // TODO: timeout for pkts to server
+            // TODO: timeout for pkts to server
+        }
+        let pto_remaining =
+            (self.last_access + self.pto_timeout()).saturating_duration_since(now);
+        Some(match queue_timeout {
+            Some(t) => t.min(pto_remaining),
+            None => pto_remaining,
+        })
     }
 
     /// Call when the timeout of the connection is reached.
     pub fn on_timeout(&mut self, now: time::Instant) {
         debug!("on_timeout, {:?}", self.elapsed(now));
-        let rtt_samples = self.to_client_queue.on_timeout(now, self.client_rtt);
+        let rtt_samples = self.to_client_queue.on_timeout(now);
         for rtt_sample in rtt_samples {
-            self.update_client_rtt(rtt_sample, now);
+            self.update_client_rtt(rtt_sample, time::Duration::ZERO, now);
+        }
+        let lossed_bytes = self.to_client_queue.take_lossed_bytes();
+        if lossed_bytes > 0 {
+            self.cc.on_loss(lossed_bytes, now);
         }
         // TODO: protect pkts to server
+
+        // separately, our own probe-timeout backoff: fires when no packet has been seen in
+        // either direction for a full PTO interval, independent of the to-client queue's
+        // flowlet-level loss/backoff bookkeeping above.
+        if !self.client_rtt.is_zero() && now.duration_since(self.last_access) >= self.pto_timeout()
+        {
+            self.pto_count += 1;
+            if self.pto_count < MAX_PTO_COUNT {
+                debug!("conn: probe timeout, backing off to pto_count {}", self.pto_count);
+            } else {
+                info!(
+                    "conn: {MAX_PTO_COUNT} consecutive probe timeouts with no packets in either direction, declaring connection dead"
+                );
+                self.dead = true;
+            }
+        }
+    }
+
+    /// Whether this connection has given up on getting a reply after `MAX_PTO_COUNT`
+    /// consecutive probe timeouts. Checked by `PEMI::remove_idle_conns` to reclaim it without
+    /// waiting out the full idle timeout.
+    pub(crate) fn is_dead(&self) -> bool {
+        self.dead
     }
 
     // Return true if need reorder this ack to influcence the sender's sending rate
@@ -529,6 +927,22 @@ impl Conn {
         self.check_delayed_acks(forward_ts);
     }
 
+    /// Packet-count threshold for releasing delayed ACKs, adaptive to the congestion window:
+    /// one reordered ACK roughly every `max(2, cwnd_pkts / ack_freq_divisor)` client packets,
+    /// instead of a flat packet count, so large-BDP flows aren't throttled to the same cadence
+    /// as small ones and small flows still get timely ACKs. `cc.cwnd()` is in bytes; divided
+    /// by the smallest packet size seen so far (or `ASSUMED_PKT_SIZE` before any packet has),
+    /// mirroring `min_pkt_size`'s existing role as this connection's packet-size estimate.
+    fn ack_freq_pkt_threshold(&self) -> usize {
+        let pkt_size = if self.min_pkt_size == usize::MAX {
+            ASSUMED_PKT_SIZE
+        } else {
+            self.min_pkt_size as f64
+        };
+        let cwnd_pkts = self.cc.cwnd() / pkt_size;
+        (cwnd_pkts / self.ack_freq_divisor).max(2.0) as usize
+    }
+
     // Check the delayed acks and send if the delay time or packet number threshold is met
     pub fn check_delayed_acks(&mut self, now: time::Instant) {
         if self.delayed_ack_queue.is_empty() {
@@ -552,20 +966,22 @@ impl Conn {
         }
 
         let front_ack = self.delayed_ack_queue.front().unwrap();
+        let base_pkt_thresh = self.ack_freq_pkt_threshold();
         let pkt_thresh;
         let time_thresh;
         if now - self.overspeed_begin.unwrap() > front_ack.e2e_rtt {
             // if overspeed lasts more than 1 e2e RTT, use more aggressive thresholds
-            pkt_thresh = DELAY_kPacketThreshold * 2;
+            pkt_thresh = base_pkt_thresh * 2;
             time_thresh = 1.0 + (DELAY_kTimeThreshold - 1.0) * 2.0;
         } else {
-            pkt_thresh = DELAY_kPacketThreshold;
+            pkt_thresh = base_pkt_thresh;
             time_thresh = DELAY_kTimeThreshold;
         }
         if self.delayed_ack_queue.len() > pkt_thresh
             || now - front_ack.forward_ts
                 > max(front_ack.e2e_rtt.mul_f64(time_thresh), DELAY_kGranularity)
         {
+            self.pending_delayed_ack_flush = Some(self.delayed_ack_queue.len());
             // 1. send the tail ack first
             let tail_ack = self.delayed_ack_queue.pop_back().unwrap();
             pemi_io::send_transparently(
@@ -597,7 +1013,33 @@ impl Conn {
     }
 
     pub fn is_idle(&self, now: time::Instant) -> bool {
-        now.duration_since(self.last_access) >= IDLE_TIMEOUT
+        now.duration_since(self.last_access) >= self.idle_timeout
+    }
+
+    /// This connection's own negotiated idle timeout, used by `PEMI::remove_idle_conns` as
+    /// a cheap pre-check before consulting `is_idle`.
+    pub(crate) fn idle_timeout(&self) -> time::Duration {
+        self.idle_timeout
+    }
+
+    /// Time remaining until this connection is considered idle, used by `PEMI::timeout` to
+    /// schedule a wakeup. Zero if already idle.
+    pub(crate) fn idle_remaining(&self, now: time::Instant) -> time::Duration {
+        self.idle_timeout
+            .saturating_sub(now.duration_since(self.last_access))
+    }
+
+    /// Current client RTT estimate, used as the clock for retransmission pacing.
+    /// Zero means not yet measured.
+    pub fn client_rtt(&self) -> time::Duration {
+        self.client_rtt
+    }
+
+    /// The client's IP address, used by `PEMI::rtt_calibration` to apply an ICMP-derived
+    /// sample only to connections whose receiver was actually probed (`PEMI::process_packet`
+    /// probes the destination of server-to-client packets, i.e. the client).
+    pub(crate) fn client_ip(&self) -> std::net::IpAddr {
+        self.client_addr.std_addr.ip()
     }
 }
 
@@ -635,4 +1077,34 @@ mod tests {
 
         assert_eq!(conn.is_idle(new_now), false);
     }
+
+    #[test]
+    fn test_stateless_reset_detection() {
+        let now = time::Instant::now();
+        let src = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1111); // client
+        let dst = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 443); // server
+        let mut conn = Conn::new(now, src, dst);
+        conn.set_handshaked();
+
+        let token = [0xab; 16];
+        conn.set_stateless_reset_token(token);
+
+        // too short to ever be a stateless reset, even with a matching trailing token
+        let mut too_short = vec![0x40u8; 4];
+        too_short.extend_from_slice(&token);
+        assert!(!conn.is_stateless_reset(&too_short));
+
+        // long enough, but the trailing bytes don't match the known token
+        let mut no_match = vec![0x40u8; 21];
+        assert!(!conn.is_stateless_reset(&no_match));
+
+        // long enough and the trailing 16 bytes match: a stateless reset
+        no_match.truncate(5);
+        no_match.extend_from_slice(&token);
+        assert!(conn.is_stateless_reset(&no_match));
+
+        let res = conn.process_quic_packet(&now, &no_match, &dst);
+        assert!(matches!(res, Err(ref e) if matches!(e.kind(), ErrorKind::StatelessReset)));
+        assert_eq!(conn.state, ConnState::Closed);
+    }
 }
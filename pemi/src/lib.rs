@@ -1,14 +1,17 @@
-mod cc;
+pub mod cc;
 pub mod common;
 pub mod conn;
 mod minmax;
+mod pacing;
 pub mod pemi_io;
+pub mod qlog;
 mod queue;
 pub mod quic_parse;
 pub mod retrans;
 mod rtt_det;
+pub mod zerocopy;
 
-use common::Error;
+use common::{Error, ErrorKind};
 
 use log::{debug, info, trace};
 
@@ -16,6 +19,7 @@ use std::time;
 
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::net::SocketAddr;
 
 #[cfg(any(feature = "cycles"))]
@@ -158,10 +162,45 @@ impl std::fmt::Display for Stats {
     }
 }
 
+/// Bytes sent so far within the current congestion-window epoch for one destination.
+/// The epoch resets every RTT, mirroring how a real sender's window "refills" as acks arrive;
+/// PEMI has no real ack feedback for its own retransmissions, so this approximates it.
+struct RetransWindow {
+    used: f64,
+    epoch_start: time::Instant,
+}
+
+impl RetransWindow {
+    /// Roll over to a fresh epoch once an RTT has elapsed since the last one began, mirroring
+    /// how a real cwnd epoch "refills" as acks arrive.
+    fn roll_epoch(&mut self, now: time::Instant, rtt: time::Duration) {
+        if now.duration_since(self.epoch_start) >= rtt.max(time::Duration::from_millis(1)) {
+            self.used = 0.0;
+            self.epoch_start = now;
+        }
+    }
+
+    /// Remaining send budget within the current epoch, in bytes, against `cwnd`. This is the
+    /// `can_send` budget `PEMI::process_retrans_task` admits packets from `detected_loss`
+    /// against before falling back to the `pacing::Pacer`/`TokenBucket` spread.
+    fn can_send(&self, cwnd: f64) -> usize {
+        (cwnd - self.used).max(0.0) as usize
+    }
+}
+
 pub struct PEMI {
     /// Connections.
     conns: HashMap<conn::ConnId, conn::Conn>,
 
+    /// Secondary index from a connection's DCID (its client-Initial DCID, and later its
+    /// server-chosen CID) to the `ConnId` it currently belongs to. Lets PEMI recognize a
+    /// connection across client migration/NAT rebinding, where the 4-tuple key changes.
+    dcid_index: HashMap<Vec<u8>, conn::ConnId>,
+
+    /// Client-chosen CID lengths observed so far, used to slice the DCID out of short-header
+    /// packets (whose CID length isn't self-describing) when probing for a migration match.
+    known_dcid_lens: HashSet<usize>,
+
     /// Active RTT detection
     pub rtt_detector: rtt_det::RttDetector,
 
@@ -182,38 +221,109 @@ pub struct PEMI {
     flowlet_interval_factor: f64,
     flowlet_end_factor: f64,
 
-    /// Retransmission rate limit.
-    retrans_rate_limit: f64,
+    /// Divisor `N` in the delayed-ack packet threshold `max(2, cwnd_pkts / N)`: reordered ACKs
+    /// are released once more than 1/N of a congestion window's worth of them are queued.
+    /// Larger values flush more often.
+    ack_freq_divisor: f64,
+
+    /// Which growth curve new `retrans_cc` entries are built with, set once via `--cc` before
+    /// any retransmission traffic flows.
+    retrans_cc_algo: cc::RetransCcAlgo,
+
+    /// Which `CongestionControl` backend new connections drive their overspeed/pacing
+    /// decision with, set once via `--conn-cc` before any connection is created.
+    conn_cc_algo: cc::ConnCcAlgo,
+
+    /// Per-destination congestion window for paced retransmission injection.
+    /// Keyed by the address the retransmission is sent to (the client, for
+    /// to-client retrans tasks). Replaces the old flat `retrans_rate_limit`.
+    retrans_cc: HashMap<SocketAddr, Box<dyn cc::CongestionControl>>,
+
+    /// Bytes already sent within the current cwnd epoch, per destination.
+    retrans_window: HashMap<SocketAddr, RetransWindow>,
+
+    /// Per-destination pacing state (kernel `SO_MAX_PACING_RATE` support cache plus the
+    /// software token-bucket fallback), so the cwnd's worth of packets released per epoch
+    /// above don't still leave the NIC in one burst.
+    retrans_pacers: HashMap<SocketAddr, pacing::Pacer>,
+
+    /// Earliest instant at which a deferred retransmission may be retried, per destination.
+    /// Consulted by `timeout()` so `process_timeout` can drain it once the window reopens.
+    retrans_deferred_until: HashMap<SocketAddr, time::Instant>,
 
     /// Is set as True, only transparent forwarding. (not enable PEMI)
     proxy_only: bool,
+
+    /// qlog-style structured event sink. `None` unless enabled via `with_qlog`, so a
+    /// production run that doesn't opt in pays only an `Option` check per event site.
+    qlog: Option<qlog::Qlog>,
 }
 
 impl PEMI {
     pub fn new() -> Self {
         PEMI {
             conns: HashMap::new(),
+            dcid_index: HashMap::new(),
+            known_dcid_lens: HashSet::new(),
             rtt_detector: rtt_det::RttDetector::new(),
             access_times: BinaryHeap::new(),
             stats: Stats::new(),
             retrans_tasks: Vec::new(),
             flowlet_interval_factor: 2.0,
             flowlet_end_factor: 2.0,
-            retrans_rate_limit: 0.1, // 1.0 means no limit, we now said 10% in the paper
+            ack_freq_divisor: 4.0,
+            retrans_cc_algo: cc::RetransCcAlgo::default(),
+            conn_cc_algo: cc::ConnCcAlgo::default(),
+            retrans_cc: HashMap::new(),
+            retrans_window: HashMap::new(),
+            retrans_pacers: HashMap::new(),
+            retrans_deferred_until: HashMap::new(),
             proxy_only: false,
+            qlog: None,
         }
     }
 
+    /// Enable qlog-style structured event emission: one newline-delimited JSON line per
+    /// connection/flowlet/retransmission/RTT event, written to wherever `qlog` is configured to
+    /// sink them (a single combined stream via `qlog::Qlog::new`, or one file per connection
+    /// via `qlog::Qlog::new_per_connection`). Disabled by default so production runs pay
+    /// nothing for it.
+    pub fn with_qlog(mut self, qlog: qlog::Qlog) -> Self {
+        self.qlog = Some(qlog);
+        self
+    }
+
     pub fn set_proxy_only(&mut self, proxy_only: bool) {
         self.proxy_only = proxy_only;
     }
 
+    /// Pick the window-growth curve for injected retransmissions (`--cc`). Only affects
+    /// destinations that haven't sent a retransmission yet; existing `retrans_cc` entries keep
+    /// the algorithm they were created with.
+    pub fn set_retrans_cc_algo(&mut self, algo: cc::RetransCcAlgo) {
+        self.retrans_cc_algo = algo;
+    }
+
+    /// Pick the congestion-control backend new connections drive their overspeed/pacing
+    /// decision with (`--conn-cc`). Only affects connections created after the call;
+    /// existing `Conn`s keep the backend they were created with.
+    pub fn set_conn_cc_algo(&mut self, algo: cc::ConnCcAlgo) {
+        self.conn_cc_algo = algo;
+    }
+
     /// Set the factors for the PEMI.
     /// flowlet_interval_factor
     /// flowlet_end_factor
-    pub fn set_factors(&mut self, flowlet_interval_factor: f64, flowlet_end_factor: f64) {
+    /// ack_freq_divisor
+    pub fn set_factors(
+        &mut self,
+        flowlet_interval_factor: f64,
+        flowlet_end_factor: f64,
+        ack_freq_divisor: f64,
+    ) {
         self.flowlet_interval_factor = flowlet_interval_factor;
         self.flowlet_end_factor = flowlet_end_factor;
+        self.ack_freq_divisor = ack_freq_divisor;
     }
 
     /// Process a UDP packet.
@@ -230,6 +340,19 @@ impl PEMI {
         let conn_id = conn::ConnId::new(srcaddr.std_addr, dstaddr.std_addr);
         trace!("pkt Conn ID: {conn_id}");
 
+        if let Some(qlog) = &mut self.qlog {
+            qlog.emit(
+                recv_ts,
+                conn_id,
+                qlog::Event::PacketReceived {
+                    src: srcaddr.std_addr,
+                    dst: dstaddr.std_addr,
+                    len: buf.len(),
+                    header_type: self.peek_header_type(&buf),
+                },
+            );
+        }
+
         let res = self.quic_conn_process(
             &buf,
             &conn_id,
@@ -238,7 +361,8 @@ impl PEMI {
             &dstaddr.std_addr,
         );
 
-        if res == Err(Error::InvalidState) || res == Err(Error::MayNotQUIC) {
+        let err_kind = res.as_ref().err().map(Error::kind);
+        if matches!(err_kind, Some(ErrorKind::InvalidState) | Some(ErrorKind::MayNotQUIC)) {
             // not a QUIC packet
             // send the packet transparently
             pemi_io::send_transparently(&srcaddr.nix_addr, &dstaddr.nix_addr, &buf);
@@ -248,6 +372,12 @@ impl PEMI {
                 res.unwrap_err()
             );
             return Ok(());
+        } else if matches!(err_kind, Some(ErrorKind::StatelessReset)) {
+            // still the server's packet to deliver; the client needs to see the reset too
+            pemi_io::send_transparently(&srcaddr.nix_addr, &dstaddr.nix_addr, &buf);
+            self.evict_conn(conn_id, recv_ts);
+            info!("conn {conn_id} evicted: stateless reset detected");
+            return Ok(());
         } else if res.is_err() {
             return res;
         }
@@ -269,7 +399,7 @@ impl PEMI {
                 // send the packet transparently
                 pemi_io::send_transparently(&srcaddr.nix_addr, &dstaddr.nix_addr, &buf);
             }
-            let new_flowlet = conn.process_udp_packet(recv_ts, &srcaddr, &dstaddr, buf);
+            let (new_flowlet, flowlet_ended) = conn.process_udp_packet(recv_ts, &srcaddr, &dstaddr, buf);
             if new_flowlet {
                 // send ICMP RTT request for the new flowlet for debug purpose. calibration used it every E2E RTT
                 self.rtt_detector.send_request(dstaddr.std_addr);
@@ -277,6 +407,50 @@ impl PEMI {
                     "recv a packet to addr: {:?}, ICMP request sent",
                     dstaddr.std_addr.ip()
                 );
+                if let Some(qlog) = &mut self.qlog {
+                    qlog.emit(recv_ts, conn_id, qlog::Event::FlowletStart);
+                }
+            }
+            if flowlet_ended {
+                if let Some(qlog) = &mut self.qlog {
+                    qlog.emit(recv_ts, conn_id, qlog::Event::FlowletEnd);
+                }
+            }
+            if let Some(qlog) = &mut self.qlog {
+                if let Some(metrics) = conn.take_cc_metrics() {
+                    qlog.emit(recv_ts, conn_id, qlog::Event::CcMetricsUpdated(metrics));
+                }
+                if let Some((client_rtt, server_rtt)) = conn.take_rtt_update() {
+                    qlog.emit(
+                        recv_ts,
+                        conn_id,
+                        qlog::Event::RttUpdated {
+                            client_rtt,
+                            server_rtt,
+                        },
+                    );
+                }
+                if let Some(direction) = conn.take_dominant_direction_change() {
+                    qlog.emit(
+                        recv_ts,
+                        conn_id,
+                        qlog::Event::DominantDirectionChanged { direction },
+                    );
+                }
+                if let Some(began) = conn.take_overspeed_change() {
+                    qlog.emit(
+                        recv_ts,
+                        conn_id,
+                        if began {
+                            qlog::Event::OverspeedBegin
+                        } else {
+                            qlog::Event::OverspeedEnd
+                        },
+                    );
+                }
+                if let Some(count) = conn.take_delayed_ack_flush() {
+                    qlog.emit(recv_ts, conn_id, qlog::Event::DelayedAckFlushed { count });
+                }
             }
         }
 
@@ -291,11 +465,38 @@ impl PEMI {
     }
 
     /// Calibrate the RTT based on the sample from RTT detector.
-    /// TODO:
-    /// Recognize the connection with the same receiver address, and only calibrate the RTT for those connections.
-    pub fn rtt_calibration(&mut self, calibration_rtt_sample: time::Duration) {
-        for (_, conn) in self.conns.iter_mut() {
+    /// The sample only reflects the path to `receiver_ip` (the ICMP probe's source), so only
+    /// connections to that receiver are calibrated; connections to other receivers keep their
+    /// passively measured RTT untouched.
+    pub fn rtt_calibration(
+        &mut self,
+        receiver_ip: std::net::IpAddr,
+        calibration_rtt_sample: time::Duration,
+    ) {
+        let now = time::Instant::now();
+        for (&conn_id, conn) in self
+            .conns
+            .iter_mut()
+            .filter(|(_, conn)| conn.client_ip() == receiver_ip)
+        {
             conn.rtt_calibration(calibration_rtt_sample);
+            if let Some(qlog) = &mut self.qlog {
+                qlog.emit(
+                    now,
+                    conn_id,
+                    qlog::Event::RttCalibration {
+                        calibration_rtt: calibration_rtt_sample,
+                        client_rtt: conn.client_rtt(),
+                    },
+                );
+                if let Some(client_min_rtt) = conn.take_rtt_reset() {
+                    qlog.emit(
+                        now,
+                        conn_id,
+                        qlog::Event::RttCalibrationReset { client_min_rtt },
+                    );
+                }
+            }
         }
     }
 
@@ -320,25 +521,145 @@ impl PEMI {
         Ok(())
     }
 
-    /// Process a retransmission task.
-    pub fn process_retrans_task(&mut self, task: &mut retrans::Task) -> Result<(), Error> {
+    /// Process a retransmission task, pacing injected retransmissions by a per-destination
+    /// congestion window instead of a flat rate cap. If the window has no room for the next
+    /// packet, the remaining packets are requeued as a task and `timeout()` will surface the
+    /// next-allowed send instant so `process_timeout` drains it once the window reopens.
+    ///
+    /// Within that per-epoch window, sends are further paced to `cc`'s `pacing_rate` so a whole
+    /// cwnd doesn't still leave in one burst: the kernel's `SO_MAX_PACING_RATE` is tried first
+    /// (see `pemi_io::send_transparently_batch_paced`), falling back to a software
+    /// `pacing::Pacer`/`TokenBucket` for destinations where the kernel doesn't honor it. Every
+    /// packet the cwnd/pacing budget admits in one call goes out in one syscall rather than one
+    /// `sendto` per packet: a uniform-size run goes out via `UDP_SEGMENT`/GSO
+    /// (`send_transparently_gso`), the kernel slicing one `sendmsg` back into datagrams, and a
+    /// mixed-size run falls back to `sendmmsg` (`send_transparently_batch`).
+    pub fn process_retrans_task(&mut self, mut task: retrans::Task) -> Result<(), Error> {
+        let dst_addr = *task.dst();
         let src = pemi_io::to_nix_addr(task.src());
         let dst = pemi_io::to_nix_addr(task.dst());
-        while let Some(pkt) = task.pop_front() {
-            let buf = pkt.payload();
-            if self.match_retrans_limit() && self.pkts() > 100 {
-                // avoid too early limit. To support initial retransmissions.
-                debug!("retransmission rate limit, skip a retransmission packet");
-                // If used for multiple connections, this need to be checked in the connection level.
-                continue;
+        let conn_id = conn::ConnId::new(*task.src(), *task.dst());
+
+        let now = time::Instant::now();
+        let rtt = self.retrans_clock_rtt(task.src(), task.dst());
+
+        let algo = self.retrans_cc_algo;
+        let cc = self
+            .retrans_cc
+            .entry(dst_addr)
+            .or_insert_with(|| algo.new_cc(now));
+        cc.update(now, rtt);
+        let rate = cc.pacing_rate(rtt);
+        let cwnd = cc.cwnd();
+
+        let window = self.retrans_window.entry(dst_addr).or_insert(RetransWindow {
+            used: 0.0,
+            epoch_start: now,
+        });
+        window.roll_epoch(now, rtt);
+
+        let pacer = self
+            .retrans_pacers
+            .entry(dst_addr)
+            .or_insert_with(|| pacing::Pacer::new(rate, cwnd, now));
+        pacer.set_rate(rate);
+
+        // Collect every packet the cwnd and (once the kernel is known not to honor
+        // SO_MAX_PACING_RATE for this destination) the software pacing budget admit right now,
+        // so they can go out in one batched send below instead of one syscall each.
+        let mut admitted = Vec::new();
+        let mut wake = None;
+        while let Some(pkt) = task.front() {
+            let len = pkt.payload().len() as f64;
+            if len > window.can_send(cc.cwnd()) as f64 {
+                wake = Some(window.epoch_start + rtt.max(time::Duration::from_millis(1)));
+                debug!("retrans cwnd exhausted for {}, deferring remaining task", dst_addr);
+                break;
+            }
+            if pacer.kernel_paced() == Some(false) && !pacer.try_consume(len, now) {
+                wake = Some(now + pacer.next_available(len));
+                debug!("retrans pacing budget exhausted for {}, deferring remaining task", dst_addr);
+                break;
+            }
+            window.used += len;
+            admitted.push(task.pop_front().expect("front() just confirmed a packet"));
+        }
+
+        if !admitted.is_empty() {
+            if let Some(segment_size) = gso_segment_size(&admitted) {
+                let mut buf = Vec::with_capacity(admitted.iter().map(|pkt| pkt.payload().len()).sum());
+                for pkt in &admitted {
+                    buf.extend_from_slice(pkt.payload());
+                }
+                if pacer.kernel_paced() == Some(false) {
+                    pemi_io::send_transparently_gso(&src, &dst, &buf, segment_size);
+                } else {
+                    let kernel_paced =
+                        pemi_io::send_transparently_gso_paced(&src, &dst, &buf, segment_size, rate);
+                    pacer.record_kernel_paced(kernel_paced);
+                }
+            } else {
+                let batch: Vec<(pemi_io::NixAddr, &[u8])> =
+                    admitted.iter().map(|pkt| (dst, pkt.payload().as_slice())).collect();
+                if pacer.kernel_paced() == Some(false) {
+                    pemi_io::send_transparently_batch(&src, &batch);
+                } else {
+                    let kernel_paced = pemi_io::send_transparently_batch_paced(&src, &batch, rate);
+                    pacer.record_kernel_paced(kernel_paced);
+                }
+            }
+            for pkt in &admitted {
+                self.stats.new_retrans_pkt(); // Increment retransmission counter
+                self.record_retrans_packet(*task.src(), *task.dst())?;
+                if let Some(qlog) = &mut self.qlog {
+                    qlog.emit(
+                        now,
+                        conn_id,
+                        qlog::Event::RetransInjected { bytes: pkt.payload().len() },
+                    );
+                }
             }
-            pemi_io::send_transparently(&src, &dst, buf);
-            self.stats.new_retrans_pkt(); // Increment retransmission counter
-            self.record_retrans_packet(*task.src(), *task.dst())?;
+        }
+
+        if let Some(wake) = wake {
+            self.retrans_deferred_until
+                .entry(dst_addr)
+                .and_modify(|w| *w = (*w).min(wake))
+                .or_insert(wake);
+            self.retrans_tasks.push(task);
         }
         Ok(())
     }
 
+    /// Whether `pkts` is a GSO-eligible run: every packet but the last the same size, and the
+    /// last no larger than the rest, the same shape `UDP_SEGMENT` requires of a send buffer.
+    /// Returns that common size, or `None` for a single packet (not worth a GSO control
+    /// message) or a run too irregular to segment.
+    fn gso_segment_size(pkts: &[queue::RawUdpPacket]) -> Option<u16> {
+        if pkts.len() < 2 {
+            return None;
+        }
+        let segment_size = pkts[0].payload().len();
+        let (head, last) = pkts.split_at(pkts.len() - 1);
+        if head.iter().all(|pkt| pkt.payload().len() == segment_size)
+            && last[0].payload().len() <= segment_size
+        {
+            u16::try_from(segment_size).ok()
+        } else {
+            None
+        }
+    }
+
+    /// RTT estimate to use as the retransmission pacer's clock: the measured client RTT of the
+    /// connection this task belongs to, falling back to a conservative default before it's measured.
+    fn retrans_clock_rtt(&self, src: &SocketAddr, dst: &SocketAddr) -> time::Duration {
+        let conn_id = conn::ConnId::new(*src, *dst);
+        match self.conns.get(&conn_id) {
+            Some(conn) if !conn.client_rtt().is_zero() => conn.client_rtt(),
+            _ => time::Duration::from_millis(50),
+        }
+    }
+
     /// Process coalesced QUIC packets. Now only for the handshake tracking.
     /// If needed, create a new connection.
     /// For new connection, if is not a QUIC Initial packet, now will return error.
@@ -356,21 +677,41 @@ impl PEMI {
         while left > 0 {
             // Process a single QUIC packet. A UDP packet may contain multiple QUIC packets.
             // On success the number of bytes processed from the input buffer is returned.
-            let read = match self.conns.get_mut(&conn_id) {
-                None => {
-                    // new connection
-                    let (conn, read) =
-                        conn::Conn::first_quic_packet(now, src, dst, &buf[len - left..len])?;
-                    self.new_conn(*conn_id, conn, *now);
-                    self.rtt_detector.fresh_begin_time(*now); // make sure the rtt detector ts is synced with the connection(only useful in debug with only one connection)
-                    info!("conn new added: {conn_id}");
-                    read
+            let read = if self.conns.contains_key(conn_id) {
+                // existing connection: process a QUIC packet by the connection
+                let c = self.conns.get_mut(conn_id).expect("just checked contains_key");
+                let read = c.process_quic_packet(now, &buf[len - left..len], src)?;
+                // the Handshake packet may have just revealed the server-chosen CID
+                if let Some(cid) = c.server_chosen_cid() {
+                    self.dcid_index.insert(cid.to_vec(), *conn_id);
+                    self.known_dcid_lens.insert(cid.len());
                 }
-                Some(c) => {
-                    // existing connection
-                    // process a QUIC packet by the connection
-                    c.process_quic_packet(now, &buf[len - left..len], src)?
+                read
+            } else if let Some(active_id) =
+                self.rebind_by_dcid(&buf[len - left..len], *conn_id, *src, *now)
+            {
+                // recognized as a migrated/rebound (or pending-validation) existing
+                // connection: process under wherever it currently lives, which is
+                // `active_id` - the new `conn_id` once validated, still the old one while
+                // the candidate's grace period hasn't elapsed yet.
+                let c = self
+                    .conns
+                    .get_mut(&active_id)
+                    .expect("rebind_by_dcid just located this conn_id");
+                let read = c.process_quic_packet(now, &buf[len - left..len], src)?;
+                if let Some(cid) = c.server_chosen_cid() {
+                    self.dcid_index.insert(cid.to_vec(), active_id);
+                    self.known_dcid_lens.insert(cid.len());
                 }
+                read
+            } else {
+                // new connection
+                let (conn, read) =
+                    conn::Conn::first_quic_packet(now, src, dst, &buf[len - left..len])?;
+                self.new_conn(*conn_id, conn, *now);
+                self.rtt_detector.fresh_begin_time(*now); // make sure the rtt detector ts is synced with the connection(only useful in debug with only one connection)
+                info!("conn new added: {conn_id}");
+                read
             };
             left -= read;
             trace!("processed {read} bytes, {left} bytes left");
@@ -380,9 +721,104 @@ impl PEMI {
 
     /// Add a new connection.
     pub fn new_conn(&mut self, conn_id: conn::ConnId, mut conn: conn::Conn, now: time::Instant) {
-        conn.set_factors(self.flowlet_interval_factor, self.flowlet_end_factor);
+        conn.set_factors(
+            self.flowlet_interval_factor,
+            self.flowlet_end_factor,
+            self.ack_freq_divisor,
+        );
+        conn.set_cc_algo(self.conn_cc_algo, now);
+        self.dcid_index
+            .insert(conn.client_initial_dcid().to_vec(), conn_id);
+        self.known_dcid_lens
+            .insert(conn.client_initial_dcid().len());
+        self.conns.insert(conn_id, conn);
+        self.access_times.push(AccessTime(now, conn_id));
+        if let Some(qlog) = &mut self.qlog {
+            qlog.emit(now, conn_id, qlog::Event::ConnAdded);
+        }
+    }
+
+    /// Best-effort QUIC header type of `buf`, for the `PacketReceived` qlog event. Long headers
+    /// are self-describing; short headers need a dcid length, so this tries every length
+    /// `known_dcid_lens` has seen so far (same fallback `rebind_by_dcid` uses). Returns
+    /// `"unknown"` rather than propagating a parse error since this is purely diagnostic.
+    fn peek_header_type(&self, buf: &[u8]) -> &'static str {
+        let hdr = quic_parse::Header::from_slice(buf, 0).ok().or_else(|| {
+            self.known_dcid_lens
+                .iter()
+                .find_map(|&dcid_len| quic_parse::Header::from_slice(buf, dcid_len).ok())
+        });
+        match hdr.map(|h| h.ty) {
+            Some(quic_parse::Type::Initial) => "initial",
+            Some(quic_parse::Type::Retry) => "retry",
+            Some(quic_parse::Type::Handshake) => "handshake",
+            Some(quic_parse::Type::ZeroRTT) => "0rtt",
+            Some(quic_parse::Type::VersionNegotiation) => "version_negotiation",
+            Some(quic_parse::Type::Short) => "short",
+            None => "unknown",
+        }
+    }
+
+    /// Check whether `buf` (the start of a UDP datagram with no existing 4-tuple match) carries
+    /// a DCID that belongs to a connection PEMI already knows under a different 4-tuple — i.e.
+    /// client migration or NAT rebinding. If so, feed `src` to that `conn::Conn`'s path
+    /// validation (`Conn::note_migration_candidate`) and return the `ConnId` the caller should
+    /// process this packet under: still `old_conn_id` while `src` is only a pending candidate
+    /// (a single matching packet could be a replayed DCID or reordering, not a real path
+    /// change), or the new `conn_id` once the candidate has persisted through the grace period
+    /// and the conn has actually been moved there.
+    fn rebind_by_dcid(
+        &mut self,
+        buf: &[u8],
+        conn_id: conn::ConnId,
+        src: SocketAddr,
+        now: time::Instant,
+    ) -> Option<conn::ConnId> {
+        // long header: dcid length is self-describing, the dcid_len arg is ignored.
+        // short header: length isn't self-describing, so try lengths seen before.
+        let dcid = quic_parse::peek_dcid(buf, 0).or_else(|| {
+            self.known_dcid_lens
+                .iter()
+                .find_map(|&dcid_len| quic_parse::peek_dcid(buf, dcid_len))
+        });
+        let dcid = dcid?;
+
+        let &old_conn_id = self.dcid_index.get(dcid)?;
+        if old_conn_id == conn_id {
+            return None; // same 4-tuple, not a migration
+        }
+        let Some(conn) = self.conns.get_mut(&old_conn_id) else {
+            // stale index entry left by a connection already removed; clean it up
+            self.dcid_index.remove(dcid);
+            return None;
+        };
+
+        if !conn.note_migration_candidate(src, now) {
+            debug!("conn {old_conn_id}: migration candidate {src} pending path validation");
+            return Some(old_conn_id);
+        }
+
+        let mut conn = self.conns.remove(&old_conn_id).expect("just located above");
+        conn.rebind(now, src);
+        self.dcid_index.insert(dcid.to_vec(), conn_id);
         self.conns.insert(conn_id, conn);
         self.access_times.push(AccessTime(now, conn_id));
+        info!("conn {old_conn_id} rebound to {conn_id} via DCID match");
+        Some(conn_id)
+    }
+
+    /// Evict a connection immediately (as opposed to waiting for `remove_idle_conns` to find
+    /// it idle), e.g. after a stateless reset. Drops any retransmissions still queued for it
+    /// so PEMI stops "helping" a connection the server has already abandoned. Leaves the
+    /// `access_times` heap entry in place; it's harmless and gets dropped lazily the next
+    /// time `remove_idle_conns` encounters a `conns` miss for this id.
+    fn evict_conn(&mut self, conn_id: conn::ConnId, now: time::Instant) {
+        self.conns.remove(&conn_id);
+        self.retrans_tasks
+            .retain(|t| conn::ConnId::new(*t.src(), *t.dst()) != conn_id);
+        if let Some(qlog) = &mut self.qlog {
+            qlog.emit(now, conn_id, qlog::Event::ConnRemoved);
+        }
     }
 
     fn remove_idle_conns(&mut self, now: time::Instant) {
@@ -396,16 +832,34 @@ impl PEMI {
                 Some(AccessTime(t, c)) => (*t, *c),
             };
 
-            if now.duration_since(oldest_time) >= IDLE_TIMEOUT {
-                // the top connection may be idle
-                // check the connection
-                let c = self
-                    .conns
-                    .get(&oldest_conn_id)
-                    .expect("the connection must exist");
+            let c = match self.conns.get(&oldest_conn_id) {
+                Some(c) => c,
+                None => {
+                    // a DCID rebind already moved this conn_id's connection elsewhere;
+                    // this heap entry is stale, just drop it
+                    self.access_times.pop();
+                    continue;
+                }
+            };
+
+            if c.is_dead() {
+                // gave up after MAX_PTO_COUNT consecutive probe timeouts; don't wait out the
+                // full idle timeout to reclaim it
+                self.evict_conn(oldest_conn_id, now);
+                self.access_times.pop();
+                info!(
+                    "conn removed (dead): {}, {} conns left",
+                    oldest_conn_id,
+                    self.conns.len(),
+                );
+                continue;
+            }
+
+            if now.duration_since(oldest_time) >= c.idle_timeout() {
+                // the top connection may be idle; consult its own negotiated idle timeout
                 if c.is_idle(now) {
                     // remove the connection
-                    self.conns.remove(&oldest_conn_id); // from the map
+                    self.evict_conn(oldest_conn_id, now); // from the map, with its queued retrans tasks
                     self.access_times.pop(); // from the heap
                     info!(
                         "conn removed: {}, {} conns left",
@@ -431,10 +885,11 @@ impl PEMI {
     pub fn timeout(&mut self) -> Option<time::Duration> {
         let now = time::Instant::now();
 
-        // connection access time timeout
-        let idle_timer = match self.access_times.peek() {
+        // connection access time timeout: the soonest any single connection's own
+        // (possibly negotiated) idle timeout elapses
+        let idle_timer = match self.conns.values().map(|c| c.idle_remaining(now)).min() {
             None => return None, // no connection now, PEMI should wait for the first packet
-            Some(AccessTime(t, _)) => IDLE_TIMEOUT.saturating_sub(now.duration_since(*t)),
+            Some(t) => t,
         };
 
         // timeout for recv the reply
@@ -443,8 +898,14 @@ impl PEMI {
             Some(t) => t,
         };
 
+        // timeout for a deferred (cwnd-exhausted) retransmission to become sendable again
+        let retrans_timeout = match self.retrans_deferred_until.values().min() {
+            None => time::Duration::MAX,
+            Some(wake) => wake.saturating_duration_since(now),
+        };
+
         // return the minimum timeout
-        let timers = [idle_timer, reply_timeout];
+        let timers = [idle_timer, reply_timeout, retrans_timeout];
         let timeout = timers.iter().min().cloned();
         timeout
     }
@@ -458,18 +919,26 @@ impl PEMI {
         self.remove_idle_conns(now);
 
         // timeout on the connections
-        for (_, conn) in self.conns.iter_mut() {
+        for (&conn_id, conn) in self.conns.iter_mut() {
             if let Some(timeout) = conn.timeout(now) {
                 if timeout.is_zero() {
                     // timeout
                     conn.on_timeout(now);
                 }
             }
+            if let Some(qlog) = &mut self.qlog {
+                if let Some(metrics) = conn.take_cc_metrics() {
+                    qlog.emit(now, conn_id, qlog::Event::CcMetricsUpdated(metrics));
+                }
+            }
             if let Some(task) = conn.to_client_retrans_task() {
                 self.retrans_tasks.push(task);
             }
         }
 
+        // reopen any deferred retransmission windows whose epoch has elapsed
+        self.retrans_deferred_until.retain(|_, wake| *wake > now);
+
         Ok(())
     }
 
@@ -486,8 +955,14 @@ impl PEMI {
         self.stats.pkts
     }
 
+    /// Get injected retransmission packets, e.g. to aggregate stats across `--workers` shards
+    /// that each keep their own `PEMI`/`Stats`.
+    pub fn retrans_pkts(&self) -> u64 {
+        self.stats.retrans_pkts
+    }
+
     /// Print the statistics.
-    pub fn print_stats(&self) {
+    pub fn print_stats(&mut self) {
         // now used information: 1. processed packets, 2. retransmission rate
         assert!(self.stats.pkts > 0);
         debug!(
@@ -495,15 +970,18 @@ impl PEMI {
             self.stats.pkts,
             self.stats.retrans_pkts as f64 / self.stats.pkts as f64
         );
-    }
-
-    /// Check if the retransmission rate is limited.
-    fn match_retrans_limit(&self) -> bool {
-        if self.stats.retrans_pkts as f64 / self.stats.pkts as f64 > self.retrans_rate_limit {
-            return true;
+        if let Some(qlog) = &mut self.qlog {
+            let now = time::Instant::now();
+            qlog.emit_global(
+                now,
+                qlog::Event::GoodputSample {
+                    pkts: self.stats.pkts,
+                    retrans_pkts: self.stats.retrans_pkts,
+                },
+            );
         }
-        false
     }
+
 }
 
 #[cfg(test)]
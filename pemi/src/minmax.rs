@@ -0,0 +1,41 @@
+/* A generic windowed-minimum filter, generalizing the windowed `min_rtt` tracking
+ * `queue::RttEstimate` does inline for a single `Duration` window. `cc::Copa` needs the same
+ * behavior twice, over windows of different lengths - and, for its standing-queue filter, a
+ * window whose length itself changes on every call - so it's pulled out here instead of
+ * duplicated.
+ */
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Tracks the minimum value observed within a trailing time window, discarding samples as they
+/// age out of it.
+pub struct Minmax<T> {
+    /// Samples within the window, oldest first.
+    samples: VecDeque<(Instant, T)>,
+    /// Returned before any sample has been folded in. `cc::Copa` seeds this with
+    /// `Duration::MAX` so the first real RTT sample always becomes the running minimum.
+    initial: T,
+}
+
+impl<T: Ord + Copy> Minmax<T> {
+    pub fn new(initial: T) -> Self {
+        Minmax {
+            samples: VecDeque::new(),
+            initial,
+        }
+    }
+
+    /// Fold in `value` observed at `now`, drop samples older than `window`, and return the
+    /// minimum value still within the window.
+    pub fn running_min(&mut self, window: Duration, now: Instant, value: T) -> T {
+        self.samples.push_back((now, value));
+        while let Some(&(ts, _)) = self.samples.front() {
+            if now.duration_since(ts) > window {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.samples.iter().map(|&(_, v)| v).min().unwrap_or(self.initial)
+    }
+}
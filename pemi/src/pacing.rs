@@ -0,0 +1,166 @@
+/* Packet pacing for PEMI's own sends (injected retransmissions): `Copa`/`Cubic` already
+ * compute a target rate (`CongestionControl::pacing_rate`), but releasing a whole congestion
+ * window at once still bursts onto the wire, which is exactly the kind of burst that confuses
+ * Mininet's virtual links (the same reason GSO is disabled on the client, see chunk1's commit
+ * history). This pairs a kernel-side pacer (`SO_MAX_PACING_RATE`, honored by the `fq` qdisc)
+ * with a software token bucket for kernels/qdiscs that don't support it.
+ */
+use std::os::fd::RawFd;
+use std::time;
+
+use log::debug;
+
+/// `SOL_SOCKET`/`SO_MAX_PACING_RATE` aren't bound by the `nix` sockopt wrappers PEMI otherwise
+/// uses, so this talks to `libc::setsockopt`/`getsockopt` directly.
+const SO_MAX_PACING_RATE: libc::c_int = 47;
+
+/// Whether `fd`'s socket family/qdisc understands `SO_MAX_PACING_RATE` at all, checked with a
+/// `getsockopt` read-back before bothering to apply a rate to every send.
+pub fn kernel_pacing_supported(fd: RawFd) -> bool {
+    let mut rate: u32 = 0;
+    let mut len = std::mem::size_of::<u32>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_MAX_PACING_RATE,
+            &mut rate as *mut u32 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    ret == 0
+}
+
+/// Ask the kernel to pace `fd`'s sends to `bytes_per_sec` (requires a qdisc that honors
+/// `SO_MAX_PACING_RATE`, e.g. `fq`; a no-op or ignored under `pfifo_fast`). Returns whether
+/// the kernel accepted the option; `false` means the caller should fall back to `TokenBucket`.
+///
+/// TODO: `SO_TXTIME`, for per-packet transmit timestamps instead of one aggregate rate, needs
+/// `sendmsg` with an `SCM_TXTIME` control message; `send_transparently` still sends via
+/// `sendto`, so there's nowhere to attach that cmsg from today without also restructuring the
+/// plain transparent-forwarding path this shares with retransmission.
+pub fn set_kernel_pacing_rate(fd: RawFd, bytes_per_sec: f64) -> bool {
+    let rate = bytes_per_sec.clamp(0.0, u32::MAX as f64) as u32;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_MAX_PACING_RATE,
+            &rate as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        true
+    } else {
+        debug!(
+            "SO_MAX_PACING_RATE rejected ({}), falling back to software pacing",
+            std::io::Error::last_os_error()
+        );
+        false
+    }
+}
+
+/// Software token-bucket shaper, one per retransmission destination, used when
+/// `SO_MAX_PACING_RATE` isn't available. Mirrors `PEMI::retrans_window`'s per-destination
+/// bookkeeping, but paces continuously instead of resetting once per RTT epoch.
+pub struct TokenBucket {
+    rate_bytes_per_sec: f64,
+    tokens: f64,
+    capacity: f64,
+    last_refill: time::Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate_bytes_per_sec: f64, capacity: f64, now: time::Instant) -> Self {
+        TokenBucket {
+            rate_bytes_per_sec,
+            tokens: capacity,
+            capacity,
+            last_refill: now,
+        }
+    }
+
+    /// Update the target rate, e.g. as `Copa`/`Cubic`'s `pacing_rate` changes with the RTT.
+    pub fn set_rate(&mut self, rate_bytes_per_sec: f64) {
+        self.rate_bytes_per_sec = rate_bytes_per_sec;
+    }
+
+    fn refill(&mut self, now: time::Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_bytes_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Whether `len` bytes may go out now. Debits the bucket on success.
+    pub fn try_consume(&mut self, len: f64, now: time::Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= len {
+            self.tokens -= len;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until `len` bytes will be available, for scheduling a retry.
+    pub fn next_available(&self, len: f64) -> time::Duration {
+        if self.tokens >= len || self.rate_bytes_per_sec <= 0.0 {
+            return time::Duration::ZERO;
+        }
+        time::Duration::from_secs_f64((len - self.tokens) / self.rate_bytes_per_sec)
+    }
+}
+
+/// Per-destination pacing state for injected retransmissions. Prefers the kernel
+/// (`SO_MAX_PACING_RATE`, via `send_transparently_paced`) and, once a destination's socket has
+/// proven the kernel doesn't honor it, falls back to shaping sends with a software
+/// `TokenBucket` instead. Only a negative kernel-support result is sticky per destination:
+/// once the kernel has failed to honor the rate, later sends skip the kernel path, while as
+/// long as it keeps honoring it every send probes and reapplies the rate again.
+pub struct Pacer {
+    kernel_paced: Option<bool>,
+    bucket: TokenBucket,
+}
+
+impl Pacer {
+    pub fn new(rate_bytes_per_sec: f64, capacity: f64, now: time::Instant) -> Self {
+        Pacer {
+            kernel_paced: None,
+            bucket: TokenBucket::new(rate_bytes_per_sec, capacity, now),
+        }
+    }
+
+    /// Update the target rate, e.g. as `Copa`/`Cubic`'s `pacing_rate` changes with the RTT.
+    /// Only affects the software fallback; the kernel rate is reapplied on every
+    /// `send_transparently_paced` call instead.
+    pub fn set_rate(&mut self, rate_bytes_per_sec: f64) {
+        self.bucket.set_rate(rate_bytes_per_sec);
+    }
+
+    /// Whether the kernel honored `SO_MAX_PACING_RATE` for this destination on the most
+    /// recent paced send (`Some(false)`: known not to honor it). `None` until a send has been
+    /// attempted, in which case the caller should still try
+    /// `send_transparently_paced` rather than consulting the software fallback.
+    pub fn kernel_paced(&self) -> Option<bool> {
+        self.kernel_paced
+    }
+
+    /// Record whether the most recent `send_transparently_paced` call was honored by the
+    /// kernel, so once it has refused, later packets to this destination skip straight to
+    /// the software fallback.
+    pub fn record_kernel_paced(&mut self, paced: bool) {
+        self.kernel_paced = Some(paced);
+    }
+
+    /// Whether `len` bytes may go out now under the software fallback. Debits the bucket on
+    /// success.
+    pub fn try_consume(&mut self, len: f64, now: time::Instant) -> bool {
+        self.bucket.try_consume(len, now)
+    }
+
+    /// How long until `len` bytes will be available under the software fallback.
+    pub fn next_available(&self, len: f64) -> time::Duration {
+        self.bucket.next_available(len)
+    }
+}
@@ -1,71 +1,382 @@
-use nix::sys::socket::sockopt::IpTransparent;
+use nix::sys::socket::sockopt::{IpTransparent, Ipv6Transparent};
 use nix::sys::socket::{bind, sendto, socket, AddressFamily, SockFlag, SockType};
-use nix::sys::socket::{setsockopt, MsgFlags, SockaddrIn};
+use nix::sys::socket::{setsockopt, MsgFlags, SockaddrIn, SockaddrIn6, SockaddrLike};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::net::SocketAddrV4;
-use std::os::fd::AsRawFd;
+use std::net::SocketAddrV6;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 
 #[cfg(any(feature = "cycles"))]
 use crate::count_cycles;
 #[cfg(any(feature = "cycles"))]
 use std::arch::x86_64::_rdtsc;
 
-use nix::libc::sockaddr_in;
-
 use log::trace;
 
-/// transparently send the payload to the destination address
-// TODO: avoid creating a new socket every time -- create a global socket pool by thread_local and RefCell. This not important now but may improve performance.
-pub fn send_transparently(srcaddr: &SockaddrIn, dstaddr: &SockaddrIn, buf: &[u8]) {
+use crate::pacing;
+
+/// A transparent-forwarding address, either IPv4 or IPv6. `send_transparently`/`Addr` used to
+/// hard-assume `SockaddrIn`, which panicked on any IPv6 peer; this wraps both nix sockaddr
+/// types so the transparent-send path can dispatch to the right socket family.
+#[derive(Clone, Copy, Debug)]
+pub enum NixAddr {
+    V4(SockaddrIn),
+    V6(SockaddrIn6),
+}
+
+thread_local! {
+    /// Per-thread pool of already-bound `IP_TRANSPARENT`/`IPV6_TRANSPARENT` sockets, keyed by
+    /// the source address they're bound to. `send_transparently` used to create, configure,
+    /// bind, and drop a fresh socket on every datagram; reusing one per source address instead
+    /// turns that into a one-time setup cost, since a bound-but-unconnected UDP socket can
+    /// `sendto` any destination. `thread_local!` (rather than a shared pool behind a `Mutex`)
+    /// matches PEMI's single-worker-per-thread model and avoids any cross-thread contention.
+    static SOCKET_POOL: RefCell<HashMap<SocketAddr, OwnedFd>> = RefCell::new(HashMap::new());
+}
+
+fn create_transparent_socket(srcaddr: &NixAddr) -> OwnedFd {
+    match srcaddr {
+        NixAddr::V4(src) => {
+            let fd = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None)
+                .expect("error creating socket");
+            setsockopt(&fd, IpTransparent, &true).expect("error setting transparency");
+            bind(fd.as_raw_fd(), src).expect("error binding to source address");
+            fd
+        }
+        NixAddr::V6(src) => {
+            let fd = socket(AddressFamily::Inet6, SockType::Datagram, SockFlag::empty(), None)
+                .expect("error creating socket");
+            setsockopt(&fd, Ipv6Transparent, &true).expect("error setting transparency");
+            bind(fd.as_raw_fd(), src).expect("error binding to source address");
+            fd
+        }
+    }
+}
+
+fn assert_same_family(srcaddr: &NixAddr, dstaddr: &NixAddr) {
+    match (srcaddr, dstaddr) {
+        (NixAddr::V4(_), NixAddr::V4(_)) | (NixAddr::V6(_), NixAddr::V6(_)) => {}
+        _ => panic!("src and dst address families must match"),
+    }
+}
+
+/// Run `f` with the raw fd of the pooled transparent socket bound to `srcaddr`, creating and
+/// binding one first if this is the first send from that address on this thread.
+fn with_pooled_socket<R>(srcaddr: &NixAddr, f: impl FnOnce(RawFd) -> R) -> R {
+    SOCKET_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        let fd = pool
+            .entry(to_std_addr(srcaddr))
+            .or_insert_with(|| create_transparent_socket(srcaddr));
+        f(fd.as_raw_fd())
+    })
+}
+
+/// transparently send the payload to the destination address, via the pooled socket for
+/// `srcaddr` (see `SOCKET_POOL`).
+pub fn send_transparently(srcaddr: &NixAddr, dstaddr: &NixAddr, buf: &[u8]) {
+    assert_same_family(srcaddr, dstaddr);
     #[cfg(any(feature = "cycles"))]
     let start_2 = unsafe { _rdtsc() };
-    let fd_send = socket(
-        AddressFamily::Inet, // now only support IPv4
-        SockType::Datagram,
-        SockFlag::empty(),
-        None,
-    )
-    .expect("error creating socket");
-
-    setsockopt(&fd_send, IpTransparent, &true).expect("error setting transparency");
-
-    // bind to source address
-    bind(fd_send.as_raw_fd(), srcaddr).expect("error binding to source address");
-
-    // send the payload to the destination address
-    let ret = sendto(fd_send.as_raw_fd(), buf, dstaddr, MsgFlags::empty())
-        .expect("error sending to destination");
-    trace!("sent {} bytes to dst", ret);
+
+    let sent = with_pooled_socket(srcaddr, |fd| send_one(fd, dstaddr, buf));
+    trace!("sent {} bytes to dst", sent);
     #[cfg(any(feature = "cycles"))]
-    count_cycles(2, start_2);
+    {
+        count_cycles(2, start_2);
+        record_send_syscalls(1, 1);
+    }
 }
 
-fn to_std_addr(addr: &SockaddrIn) -> SocketAddr {
-    let ip = addr.ip().into();
-    let port = addr.port();
-    SocketAddr::new(ip, port)
+fn send_one(fd: RawFd, dstaddr: &NixAddr, buf: &[u8]) -> usize {
+    match dstaddr {
+        NixAddr::V4(dst) => {
+            sendto(fd, buf, dst, MsgFlags::empty()).expect("error sending to destination")
+        }
+        NixAddr::V6(dst) => {
+            sendto(fd, buf, dst, MsgFlags::empty()).expect("error sending to destination")
+        }
+    }
+}
+
+/// Same as `send_transparently`, but asks the kernel to pace the send to `bytes_per_sec`
+/// first (`SO_MAX_PACING_RATE`, needs the `fq` qdisc to actually honor it). Used for PEMI's
+/// own injected retransmissions, which otherwise burst out a whole congestion window at once.
+/// Returns whether the kernel accepted the pacing rate; the caller falls back to a software
+/// `pacing::TokenBucket` when it doesn't (see `PEMI::process_retrans_task`).
+pub fn send_transparently_paced(
+    srcaddr: &NixAddr,
+    dstaddr: &NixAddr,
+    buf: &[u8],
+    bytes_per_sec: f64,
+) -> bool {
+    assert_same_family(srcaddr, dstaddr);
+    let (sent, paced) = with_pooled_socket(srcaddr, |fd| {
+        let paced = pacing::kernel_pacing_supported(fd)
+            && pacing::set_kernel_pacing_rate(fd, bytes_per_sec);
+        (send_one(fd, dstaddr, buf), paced)
+    });
+    trace!("sent {} bytes to dst (kernel-paced: {})", sent, paced);
+    #[cfg(any(feature = "cycles"))]
+    record_send_syscalls(1, 1);
+    paced
+}
+
+/// Batched counterpart to `send_transparently`: sends every `(dstaddr, buf)` pair in `batch`
+/// from the shared pooled socket for `srcaddr` in one `sendmmsg` syscall, for callers that
+/// already have several transparently-forwarded datagrams from the same source queued up
+/// (e.g. `PEMI::process_retrans_task` draining a flowlet's worth of retransmissions at once).
+/// Returns the number of datagrams the kernel reports as sent. `nix` doesn't wrap `sendmmsg`,
+/// so this goes straight to `libc`, same as `pacing`'s `SO_MAX_PACING_RATE` calls.
+pub fn send_transparently_batch(srcaddr: &NixAddr, batch: &[(NixAddr, &[u8])]) -> usize {
+    if batch.is_empty() {
+        return 0;
+    }
+    for (dstaddr, _) in batch {
+        assert_same_family(srcaddr, dstaddr);
+    }
+    let sent = with_pooled_socket(srcaddr, |fd| sendmmsg_batch(fd, batch));
+    trace!("sent {} of {} batched datagrams", sent, batch.len());
+    #[cfg(any(feature = "cycles"))]
+    record_send_syscalls(1, batch.len() as u64);
+    sent
 }
 
-pub fn to_nix_addr(addr: &SocketAddr) -> SockaddrIn {
-    let ip = addr.ip();
-    let port = addr.port();
-    // use std::net::Ipv4Addr as middle type
-    let addr: SocketAddrV4 = match ip {
-        std::net::IpAddr::V4(ip) => SocketAddrV4::new(ip, port),
-        _ => panic!("only support IPv4 now"),
+/// Same as `send_transparently_batch`, but applies `bytes_per_sec` via `SO_MAX_PACING_RATE`
+/// before the `sendmmsg` call. Returns whether the kernel accepted the pacing rate; the
+/// caller falls back to `pacing::Pacer`'s software token bucket when it doesn't.
+pub fn send_transparently_batch_paced(
+    srcaddr: &NixAddr,
+    batch: &[(NixAddr, &[u8])],
+    bytes_per_sec: f64,
+) -> bool {
+    if batch.is_empty() {
+        return true;
+    }
+    for (dstaddr, _) in batch {
+        assert_same_family(srcaddr, dstaddr);
+    }
+    let paced = with_pooled_socket(srcaddr, |fd| {
+        let paced = pacing::kernel_pacing_supported(fd)
+            && pacing::set_kernel_pacing_rate(fd, bytes_per_sec);
+        sendmmsg_batch(fd, batch);
+        paced
+    });
+    trace!("sent {} batched datagrams (kernel-paced: {})", batch.len(), paced);
+    #[cfg(any(feature = "cycles"))]
+    record_send_syscalls(1, batch.len() as u64);
+    paced
+}
+
+/// `SOL_UDP`/`UDP_SEGMENT` aren't bound by the `nix` sockopt/cmsg types PEMI otherwise uses
+/// (same reasoning as `pacing::SO_MAX_PACING_RATE`), so GSO sends go straight to `libc`.
+const SOL_UDP: libc::c_int = 17;
+const UDP_SEGMENT: libc::c_int = 103;
+
+/// Send `buf` to `dstaddr` as a single `sendmsg` carrying a `UDP_SEGMENT` control message, so
+/// the kernel slices it back into `segment_size`-byte datagrams (the final segment may be
+/// shorter) instead of one syscall per packet. Used for runs of same-destination retransmission
+/// packets that are already uniform size (see `PEMI::process_retrans_task`); callers must fall
+/// back to `send_transparently_batch` for runs that aren't. Returns the number of bytes sent.
+pub fn send_transparently_gso(srcaddr: &NixAddr, dstaddr: &NixAddr, buf: &[u8], segment_size: u16) -> usize {
+    assert_same_family(srcaddr, dstaddr);
+    let sent = with_pooled_socket(srcaddr, |fd| sendmsg_gso(fd, dstaddr, buf, segment_size));
+    trace!(
+        "sent {} bytes to dst as GSO segments of {}",
+        sent,
+        segment_size
+    );
+    #[cfg(any(feature = "cycles"))]
+    record_send_syscalls(1, buf.len().div_ceil(segment_size as usize) as u64);
+    sent
+}
+
+/// Same as `send_transparently_gso`, but applies `bytes_per_sec` via `SO_MAX_PACING_RATE`
+/// first. Returns whether the kernel accepted the pacing rate; the caller falls back to a
+/// software `pacing::Pacer` when it doesn't.
+pub fn send_transparently_gso_paced(
+    srcaddr: &NixAddr,
+    dstaddr: &NixAddr,
+    buf: &[u8],
+    segment_size: u16,
+    bytes_per_sec: f64,
+) -> bool {
+    assert_same_family(srcaddr, dstaddr);
+    let paced = with_pooled_socket(srcaddr, |fd| {
+        let paced = pacing::kernel_pacing_supported(fd)
+            && pacing::set_kernel_pacing_rate(fd, bytes_per_sec);
+        sendmsg_gso(fd, dstaddr, buf, segment_size);
+        paced
+    });
+    trace!(
+        "sent {} bytes to dst as GSO segments of {} (kernel-paced: {})",
+        buf.len(),
+        segment_size,
+        paced
+    );
+    #[cfg(any(feature = "cycles"))]
+    record_send_syscalls(1, buf.len().div_ceil(segment_size as usize) as u64);
+    paced
+}
+
+/// Attach a `UDP_SEGMENT` control message carrying `segment_size` and send `buf` to `dstaddr`
+/// in one `sendmsg` syscall. `fd` is assumed already bound/transparent.
+fn sendmsg_gso(fd: RawFd, dstaddr: &NixAddr, buf: &[u8], segment_size: u16) -> usize {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // big enough for one cmsghdr plus a u16 payload, rounded up to word alignment.
+    let mut cmsg_buf = [0u8; 32];
+    let controllen = unsafe { libc::CMSG_SPACE(std::mem::size_of::<u16>() as u32) as usize };
+    let msg_hdr = libc::msghdr {
+        msg_name: dstaddr.as_ptr() as *mut libc::c_void,
+        msg_namelen: dstaddr.len(),
+        msg_iov: &mut iov as *mut libc::iovec,
+        msg_iovlen: 1,
+        msg_control: cmsg_buf.as_mut_ptr() as *mut libc::c_void,
+        msg_controllen: controllen,
+        msg_flags: 0,
     };
-    SockaddrIn::from(addr)
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg_hdr);
+        (*cmsg).cmsg_level = SOL_UDP;
+        (*cmsg).cmsg_type = UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u16>() as u32) as usize;
+        (libc::CMSG_DATA(cmsg) as *mut u16).write_unaligned(segment_size);
+    }
+    let ret = unsafe { libc::sendmsg(fd, &msg_hdr, 0) };
+    if ret < 0 {
+        panic!(
+            "sendmsg (UDP_SEGMENT) failed for {} bytes: {}",
+            buf.len(),
+            std::io::Error::last_os_error()
+        );
+    }
+    ret as usize
+}
+
+/// Send every `(dstaddr, buf)` pair in `batch` from `fd` in one `sendmmsg` syscall. `fd` is
+/// assumed already bound/transparent; only the destination varies per message.
+fn sendmmsg_batch(fd: RawFd, batch: &[(NixAddr, &[u8])]) -> usize {
+    let mut iovecs: Vec<libc::iovec> = batch
+        .iter()
+        .map(|(_, buf)| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = batch
+        .iter()
+        .zip(iovecs.iter_mut())
+        .map(|((dst, _), iov)| libc::mmsghdr {
+            msg_hdr: libc::msghdr {
+                msg_name: dst.as_ptr() as *mut libc::c_void,
+                msg_namelen: dst.len(),
+                msg_iov: iov as *mut libc::iovec,
+                msg_iovlen: 1,
+                msg_control: std::ptr::null_mut(),
+                msg_controllen: 0,
+                msg_flags: 0,
+            },
+            msg_len: 0,
+        })
+        .collect();
+    let ret = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as u32, 0) };
+    if ret < 0 {
+        panic!(
+            "sendmmsg failed for a batch of {}: {}",
+            batch.len(),
+            std::io::Error::last_os_error()
+        );
+    }
+    ret as usize
+}
+
+impl NixAddr {
+    fn as_ptr(&self) -> *const libc::sockaddr {
+        match self {
+            NixAddr::V4(addr) => addr.as_ptr(),
+            NixAddr::V6(addr) => addr.as_ptr(),
+        }
+    }
+
+    fn len(&self) -> libc::socklen_t {
+        match self {
+            NixAddr::V4(addr) => addr.len(),
+            NixAddr::V6(addr) => addr.len(),
+        }
+    }
+}
+
+/// Number of `sendmmsg`/`sendto` syscalls issued for transparent forwarding, and the number
+/// of datagrams they covered, since startup. Lets a benchmark compare the pre-batching
+/// one-syscall-per-packet cost (`send_transparently`/`send_transparently_paced`) against the
+/// batched path (`send_transparently_batch`/`_paced`); see `print_send_syscall_summary`.
+#[cfg(any(feature = "cycles"))]
+static mut SEND_SYSCALLS: u64 = 0;
+#[cfg(any(feature = "cycles"))]
+static mut SEND_DATAGRAMS: u64 = 0;
+
+#[cfg(any(feature = "cycles"))]
+fn record_send_syscalls(syscalls: u64, datagrams: u64) {
+    unsafe {
+        SEND_SYSCALLS += syscalls;
+        SEND_DATAGRAMS += datagrams;
+    }
 }
 
-pub fn print_addr(addr: &sockaddr_in) -> String {
-    let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
-    let port = u16::from_be(addr.sin_port);
-    format!("{}:{}", ip, port)
+#[cfg(any(feature = "cycles"))]
+static mut SEND_SUMMARY_CALLS: u64 = 0;
+
+/// Print the syscalls-per-datagram ratio accumulated by `record_send_syscalls`, so batching's
+/// effect on the transparent-send path is directly measurable under the `cycles` feature.
+/// Called once per processed packet alongside `print_cycles_count_summary`, so this keeps its
+/// own every-100th-call throttle to match that function's print cadence.
+#[cfg(any(feature = "cycles"))]
+pub fn print_send_syscall_summary() {
+    unsafe {
+        SEND_SUMMARY_CALLS += 1;
+        if SEND_SUMMARY_CALLS % 100 == 0 && SEND_SYSCALLS > 0 {
+            println!(
+                "transparent send: {} syscalls for {} datagrams ({:.2} datagrams/syscall)",
+                SEND_SYSCALLS,
+                SEND_DATAGRAMS,
+                SEND_DATAGRAMS as f64 / SEND_SYSCALLS as f64
+            );
+        }
+    }
+}
+
+fn to_std_addr(addr: &NixAddr) -> SocketAddr {
+    match addr {
+        NixAddr::V4(addr) => SocketAddr::new(addr.ip().into(), addr.port()),
+        NixAddr::V6(addr) => SocketAddr::new(addr.ip().into(), addr.port()),
+    }
+}
+
+pub fn to_nix_addr(addr: &SocketAddr) -> NixAddr {
+    match addr {
+        SocketAddr::V4(addr) => NixAddr::V4(SockaddrIn::from(*addr)),
+        SocketAddr::V6(addr) => {
+            // drop flowinfo/scope_id: PEMI only needs ip+port to bind/sendto transparently.
+            let addr = SocketAddrV6::new(*addr.ip(), addr.port(), 0, 0);
+            NixAddr::V6(SockaddrIn6::from(addr))
+        }
+    }
+}
+
+pub fn print_addr(addr: &NixAddr) -> String {
+    match addr {
+        NixAddr::V4(addr) => format!("{}:{}", addr.ip(), addr.port()),
+        NixAddr::V6(addr) => format!("[{}]:{}", addr.ip(), addr.port()),
+    }
 }
 
 pub struct Addr {
     pub std_addr: SocketAddr,
-    pub nix_addr: SockaddrIn,
+    pub nix_addr: NixAddr,
 }
 
 impl Addr {
@@ -77,7 +388,7 @@ impl Addr {
         }
     }
 
-    pub fn from_nix_addr(addr: SockaddrIn) -> Self {
+    pub fn from_nix_addr(addr: NixAddr) -> Self {
         let std_addr = to_std_addr(&addr);
         Addr {
             std_addr,
@@ -85,3 +396,4 @@ impl Addr {
         }
     }
 }
+
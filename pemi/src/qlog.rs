@@ -0,0 +1,280 @@
+/* qlog-style structured event stream, for offline correlation of flowlet decisions,
+ * injected retransmissions, and RTT measurements across a PEMI run.
+ *
+ * Loosely modeled on https://datatracker.ietf.org/doc/html/draft-ietf-quic-qlog-main-schema:
+ * newline-delimited JSON, one event per line, each carrying a timestamp relative to when the
+ * stream was opened. PEMI doesn't pull in a JSON crate elsewhere, so events are formatted by
+ * hand to match the repo's existing no-dependency-for-this style.
+ */
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time;
+
+use log::warn;
+
+use crate::cc::CopaMetrics;
+use crate::conn::ConnId;
+
+/// One qlog event. `Qlog::emit` serializes this to a single newline-delimited JSON line.
+pub enum Event {
+    /// A new connection was added to PEMI's tracking (`PEMI::new_conn`).
+    ConnAdded,
+
+    /// A connection was evicted from PEMI's tracking (`PEMI::evict_conn`): idle, declared
+    /// dead after repeated probe timeouts, or closed by a stateless reset.
+    ConnRemoved,
+
+    /// A UDP datagram was received and handed to `PEMI::process_packet` (the very first
+    /// thing that happens to it, before QUIC parsing decides how it's handled).
+    PacketReceived {
+        src: SocketAddr,
+        dst: SocketAddr,
+        len: usize,
+        header_type: &'static str,
+    },
+
+    /// A new protected flowlet began (the `new_flowlet` path in `PEMI::process_packet`).
+    FlowletStart,
+
+    /// A protected flowlet was marked complete and its packets removed from the queue (the
+    /// `flowlet_ended` path in `PEMI::process_packet`, fed by `queue::PacketQueue::check_reply`).
+    FlowletEnd,
+
+    /// A retransmission was injected toward the client (`PEMI::process_retrans_task`).
+    RetransInjected { bytes: usize },
+
+    /// An RTT calibration sample was folded into a connection (`PEMI::rtt_calibration`,
+    /// fed by `rtt_detector`'s ICMP probe replies).
+    RttCalibration {
+        calibration_rtt: time::Duration,
+        client_rtt: time::Duration,
+    },
+
+    /// One `Copa::on_ack_send` decision (`Conn::take_cc_metrics`, pulled after every UDP
+    /// packet/timeout that fed the controller an RTT sample). Follows the qlog
+    /// `recovery:metrics_updated`/`recovery:congestion_state_updated` schema.
+    CcMetricsUpdated(CopaMetrics),
+
+    /// A periodic goodput sample across all connections (`PEMI::print_stats`), emitted on the
+    /// same `--print-interval` cadence as the plain-text stats line.
+    GoodputSample { pkts: u64, retrans_pkts: u64 },
+
+    /// A fresh client/server smoothed-RTT estimate landed (`Conn::take_rtt_update`, fed by
+    /// `update_client_rtt`/`update_server_rtt`).
+    RttUpdated {
+        client_rtt: time::Duration,
+        server_rtt: time::Duration,
+    },
+
+    /// `Conn::measure_dominant_direction` flipped which side is sending the bulk of the
+    /// traffic (`Conn::take_dominant_direction_change`).
+    DominantDirectionChanged { direction: &'static str },
+
+    /// The overspeed heuristic (`Conn::take_overspeed_change`) started or stopped reordering
+    /// ACKs to slow the sender down.
+    OverspeedBegin,
+    OverspeedEnd,
+
+    /// `Conn::check_delayed_acks` released a batch of reordered ACKs (`Conn::take_delayed_ack_flush`).
+    DelayedAckFlushed { count: usize },
+
+    /// `Conn::rtt_calibration` found the calibration sample deviated enough from the tracked
+    /// client RTT to reset `client_min_rtt` and the CC's RTT filters (`Conn::take_rtt_reset`).
+    RttCalibrationReset { client_min_rtt: time::Duration },
+}
+
+impl Event {
+    fn category_and_fields(&self) -> (&'static str, String) {
+        match self {
+            Event::ConnAdded => ("conn_added", String::new()),
+            Event::ConnRemoved => ("conn_removed", String::new()),
+            Event::PacketReceived {
+                src,
+                dst,
+                len,
+                header_type,
+            } => (
+                "packet_received",
+                format!(
+                    r#","src":"{src}","dst":"{dst}","len":{len},"header_type":"{header_type}""#
+                ),
+            ),
+            Event::FlowletStart => ("flowlet_start", String::new()),
+            Event::FlowletEnd => ("flowlet_end", String::new()),
+            Event::RetransInjected { bytes } => {
+                ("retrans_injected", format!(r#","bytes":{bytes}"#))
+            }
+            Event::RttCalibration {
+                calibration_rtt,
+                client_rtt,
+            } => (
+                "rtt_calibration",
+                format!(
+                    r#","calibration_rtt_ms":{:.3},"client_rtt_ms":{:.3}"#,
+                    calibration_rtt.as_secs_f64() * 1000.0,
+                    client_rtt.as_secs_f64() * 1000.0
+                ),
+            ),
+            Event::CcMetricsUpdated(m) => (
+                "cc_metrics_updated",
+                format!(
+                    r#","cwnd":{:.0},"rtt_min_ms":{:.3},"rtt_standing_ms":{:.3},"dq_ms":{:.3},"lambda":{:.3},"lambda_t":{:.3},"v":{:.3},"direction":"{}","slow_start":{},"pacing_rate_bps":{:.0}"#,
+                    m.cwnd,
+                    m.rtt_min.as_secs_f64() * 1000.0,
+                    m.rtt_standing.as_secs_f64() * 1000.0,
+                    m.dq.as_secs_f64() * 1000.0,
+                    m.lambda,
+                    m.lambda_t,
+                    m.v,
+                    m.direction,
+                    m.slow_start,
+                    m.pacing_rate,
+                ),
+            ),
+            Event::GoodputSample { pkts, retrans_pkts } => (
+                "goodput_sample",
+                format!(r#","pkts":{pkts},"retrans_pkts":{retrans_pkts}"#),
+            ),
+            Event::RttUpdated {
+                client_rtt,
+                server_rtt,
+            } => (
+                "rtt_updated",
+                format!(
+                    r#","client_rtt_ms":{:.3},"server_rtt_ms":{:.3}"#,
+                    client_rtt.as_secs_f64() * 1000.0,
+                    server_rtt.as_secs_f64() * 1000.0
+                ),
+            ),
+            Event::DominantDirectionChanged { direction } => (
+                "dominant_direction_changed",
+                format!(r#","direction":"{direction}""#),
+            ),
+            Event::OverspeedBegin => ("overspeed_begin", String::new()),
+            Event::OverspeedEnd => ("overspeed_end", String::new()),
+            Event::DelayedAckFlushed { count } => {
+                ("delayed_ack_flushed", format!(r#","count":{count}"#))
+            }
+            Event::RttCalibrationReset { client_min_rtt } => (
+                "rtt_calibration_reset",
+                format!(
+                    r#","client_min_rtt_ms":{:.3}"#,
+                    client_min_rtt.as_secs_f64() * 1000.0
+                ),
+            ),
+        }
+    }
+}
+
+/// Where `Qlog` writes its event lines.
+enum Sink {
+    /// Every connection's events interleaved into one stream, the original behavior.
+    Single(Box<dyn Write + Send>),
+
+    /// One NDJSON file per connection under `dir`, named after the connection's 4-tuple, plus
+    /// a `global.qlog.jsonl` for events not scoped to a connection (`emit_global`). Files are
+    /// opened lazily, on the first event seen for a given connection.
+    PerConnection {
+        dir: PathBuf,
+        writers: HashMap<ConnId, Box<dyn Write + Send>>,
+        global: Option<Box<dyn Write + Send>>,
+    },
+}
+
+/// Emits qlog events to a configurable writer. Only constructed when a caller opts in via
+/// `PEMI::with_qlog`, so a disabled PEMI pays nothing beyond an `Option` check per event site.
+pub struct Qlog {
+    sink: Sink,
+    begin: time::Instant,
+}
+
+impl Qlog {
+    /// Interleave every connection's events into a single `writer`.
+    pub fn new(writer: Box<dyn Write + Send>, now: time::Instant) -> Self {
+        Qlog {
+            sink: Sink::Single(writer),
+            begin: now,
+        }
+    }
+
+    /// Split events into one file per connection under `dir`, so qvis-style tooling can load a
+    /// single connection's qlog stream without filtering it out of a shared one. `dir` must
+    /// already exist; files inside it are created on demand.
+    pub fn new_per_connection(dir: PathBuf, now: time::Instant) -> Self {
+        Qlog {
+            sink: Sink::PerConnection {
+                dir,
+                writers: HashMap::new(),
+                global: None,
+            },
+            begin: now,
+        }
+    }
+
+    /// Emit one event, timestamped relative to when this `Qlog` was created. Write failures
+    /// are logged and otherwise ignored: a broken qlog sink must never affect forwarding.
+    pub fn emit(&mut self, now: time::Instant, conn_id: ConnId, event: Event) {
+        let ts_ms = now.duration_since(self.begin).as_secs_f64() * 1000.0;
+        let (category, fields) = event.category_and_fields();
+        let line =
+            format!(r#"{{"ts_ms":{ts_ms:.3},"category":"{category}","conn_id":"{conn_id}"{fields}}}"#);
+        let res = match &mut self.sink {
+            Sink::Single(writer) => writeln!(writer, "{line}"),
+            Sink::PerConnection { dir, writers, .. } => {
+                match Self::writer_for(dir, writers, conn_id) {
+                    Ok(writer) => writeln!(writer, "{line}"),
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        if let Err(e) = res {
+            warn!("qlog write failed, dropping event: {e}");
+        }
+    }
+
+    /// Emit one event that isn't scoped to a single connection (e.g. `GoodputSample`, which
+    /// is aggregated across all of `PEMI`'s connections), so there's no `conn_id` field.
+    pub fn emit_global(&mut self, now: time::Instant, event: Event) {
+        let ts_ms = now.duration_since(self.begin).as_secs_f64() * 1000.0;
+        let (category, fields) = event.category_and_fields();
+        let line = format!(r#"{{"ts_ms":{ts_ms:.3},"category":"{category}"{fields}}}"#);
+        let res = match &mut self.sink {
+            Sink::Single(writer) => writeln!(writer, "{line}"),
+            Sink::PerConnection { dir, global, .. } => match Self::global_writer(dir, global) {
+                Ok(writer) => writeln!(writer, "{line}"),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = res {
+            warn!("qlog write failed, dropping event: {e}");
+        }
+    }
+
+    /// Get (opening on first use) the per-connection file for `conn_id`.
+    fn writer_for<'a>(
+        dir: &PathBuf,
+        writers: &'a mut HashMap<ConnId, Box<dyn Write + Send>>,
+        conn_id: ConnId,
+    ) -> std::io::Result<&'a mut (dyn Write + Send)> {
+        if !writers.contains_key(&conn_id) {
+            let path = dir.join(format!("{}.qlog.jsonl", conn_id.filename_safe()));
+            let file = std::fs::File::create(path)?;
+            writers.insert(conn_id, Box::new(std::io::BufWriter::new(file)));
+        }
+        Ok(writers.get_mut(&conn_id).expect("just inserted").as_mut())
+    }
+
+    /// Get (opening on first use) the `global.qlog.jsonl` file for connection-less events.
+    fn global_writer<'a>(
+        dir: &PathBuf,
+        global: &'a mut Option<Box<dyn Write + Send>>,
+    ) -> std::io::Result<&'a mut (dyn Write + Send)> {
+        if global.is_none() {
+            let file = std::fs::File::create(dir.join("global.qlog.jsonl"))?;
+            *global = Some(Box::new(std::io::BufWriter::new(file)));
+        }
+        Ok(global.as_mut().expect("just set").as_mut())
+    }
+}
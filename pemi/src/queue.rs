@@ -22,6 +22,230 @@ const THRESHOLD_FOR_1_ELICITING_THRESHOLD: f64 = 0.6; // if reply ratio > this v
 /// Duration ratio threshold to decide whether the lost pkts are not edge pkts; this decide the used RTT for sent-reply matching, and whether we use the RTT samples from a flowlet.
 const DURATION_RATIO_THRESHOLD: f64 = 0.8;
 
+/// Window over which `RttEstimate` tracks `min_rtt`; samples older than this age out.
+const MIN_RTT_WINDOW: time::Duration = time::Duration::from_secs(10);
+
+/// Packet-reordering threshold for incremental, mid-flowlet loss detection (RFC 9002's
+/// kPacketThreshold): a sent packet still missing a reply is a loss candidate once it was sent
+/// at least this many packets before the latest-sent packet in the same flowlet that has one.
+const RTT_PACKET_THRESHOLD: usize = 3;
+
+/// Time threshold for incremental, mid-flowlet loss detection (RFC 9002's kTimeThreshold,
+/// 9/8): a sent packet still missing a reply is a loss candidate once this much longer than
+/// the measured RTT has elapsed since it was forwarded.
+const RTT_TIME_THRESHOLD: f64 = 9.0 / 8.0;
+
+/// Floor under the RTT-variance term of the PTO base, matching RFC 9002's kGranularity.
+const PTO_GRANULARITY: time::Duration = time::Duration::from_millis(1);
+
+/// Cap on consecutive probe-timeout backoffs for the front flowlet before it's abandoned,
+/// mirroring how `FLOWLET_MAX_PKTS` caps protection by packet count instead of time.
+const MAX_PTO_COUNT: u32 = 6;
+
+/// Number of the front flowlet's most-recently-sent, still-unreplied packets a PTO backoff
+/// probes with, QUIC PTO-style, instead of declaring the whole flowlet lost on the first
+/// missed deadline.
+const PTO_PROBE_COUNT: usize = 2;
+
+/// How long a declared-lost packet's record is kept around to recognize a late, spurious
+/// match for it; entries older than this are dropped without being counted as spurious.
+const RECENTLY_LOST_WINDOW: time::Duration = time::Duration::from_secs(2);
+
+/// Cap on how far `detect_incremental_loss`'s packet-reordering threshold can grow above
+/// `RTT_PACKET_THRESHOLD` in response to observed spurious losses.
+const MAX_PKT_REORDER_GROWTH: usize = 3;
+
+/// RFC 9002 section 5.3-style RTT estimator, fed by the RTT samples `Flowlet` extracts from
+/// matched sent/reply packets. Replaces the `reply_rtt: Duration` that `PacketQueue` used to
+/// take from the caller on every call, so flowlet matching and timeout decisions adapt to the
+/// connection's own measured variance instead of trusting a constant handed in from outside.
+#[derive(Debug)]
+struct RttEstimate {
+    /// Most recent RTT sample.
+    latest_rtt: time::Duration,
+    /// Minimum RTT observed within `MIN_RTT_WINDOW`.
+    min_rtt: time::Duration,
+    /// EWMA of RTT samples.
+    smoothed_rtt: time::Duration,
+    /// EWMA of the mean deviation of samples from `smoothed_rtt`.
+    rttvar: time::Duration,
+    /// Samples within `MIN_RTT_WINDOW`, oldest first, used to recompute `min_rtt` as samples
+    /// age out.
+    window: VecDeque<(time::Instant, time::Duration)>,
+}
+
+impl RttEstimate {
+    fn new() -> Self {
+        RttEstimate {
+            latest_rtt: time::Duration::ZERO,
+            min_rtt: time::Duration::MAX,
+            smoothed_rtt: time::Duration::ZERO,
+            rttvar: time::Duration::ZERO,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// Whether at least one sample has been folded in.
+    fn has_sample(&self) -> bool {
+        !self.window.is_empty()
+    }
+
+    /// Fold in a new RTT sample observed at `now`.
+    fn update(&mut self, sample: time::Duration, now: time::Instant) {
+        if !self.has_sample() {
+            // first sample
+            self.smoothed_rtt = sample;
+            self.rttvar = sample / 2;
+        } else {
+            // A sample shorter than every RTT measured recently is more likely a mismatched
+            // sent/reply pairing than a genuine latency drop below `min_rtt`, so clamp it
+            // before folding it into the smoothed estimate: `min_rtt` itself still tracks the
+            // raw sample below, but one bad flowlet match can't drag `smoothed_rtt`/`rttvar`
+            // down with it.
+            let clamped_sample = sample.max(self.min_rtt);
+            let deviation = if self.smoothed_rtt > clamped_sample {
+                self.smoothed_rtt - clamped_sample
+            } else {
+                clamped_sample - self.smoothed_rtt
+            };
+            self.rttvar = self.rttvar.mul_f64(0.75) + deviation.mul_f64(0.25);
+            self.smoothed_rtt = self.smoothed_rtt.mul_f64(0.875) + clamped_sample.mul_f64(0.125);
+        }
+        self.latest_rtt = sample;
+
+        self.window.push_back((now, sample));
+        while let Some(&(ts, _)) = self.window.front() {
+            if now.duration_since(ts) > MIN_RTT_WINDOW {
+                self.window.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.min_rtt = self.window.iter().map(|&(_, d)| d).min().unwrap();
+    }
+
+    /// True if `sample` deviates from the smoothed estimate enough to indicate a real RTT
+    /// shift rather than normal jitter (RFC 9002-style `smoothed_rtt + 4 * rttvar` bound).
+    /// Always false before the first sample, since there is nothing yet to deviate from.
+    fn deviates(&self, sample: time::Duration) -> bool {
+        self.has_sample() && sample > self.smoothed_rtt + self.rttvar * 4
+    }
+
+    /// Current smoothed RTT estimate, `Duration::ZERO` before the first sample.
+    fn smoothed_rtt(&self) -> time::Duration {
+        self.smoothed_rtt
+    }
+
+    /// Current EWMA of the mean deviation of samples from `smoothed_rtt`.
+    fn rttvar(&self) -> time::Duration {
+        self.rttvar
+    }
+
+    /// Minimum RTT observed within `MIN_RTT_WINDOW`, `Duration::MAX` before the first sample.
+    fn min_rtt(&self) -> time::Duration {
+        self.min_rtt
+    }
+}
+
+/// Coalescing tracker of packet numbers seen so far, backed by a `BTreeMap` from each
+/// contiguous run's start to its inclusive end; an insert merges into a bordering run instead
+/// of growing an append-only list. Gives O(log n) `contains`/gap queries regardless of how
+/// reordered the inserts arrive, which a flat `Vec` of packet numbers can't.
+#[derive(Debug, Default)]
+struct RangeTracker {
+    // run start -> inclusive run end
+    ranges: std::collections::BTreeMap<u64, u64>,
+}
+
+impl RangeTracker {
+    fn new() -> Self {
+        RangeTracker {
+            ranges: std::collections::BTreeMap::new(),
+        }
+    }
+
+    /// Record `pkt_num`, merging it into a bordering/overlapping run if one exists.
+    fn insert(&mut self, pkt_num: u64) {
+        let mut start = pkt_num;
+        let mut end = pkt_num;
+
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..=pkt_num).next_back() {
+            if prev_end + 1 >= pkt_num {
+                start = prev_start;
+                end = prev_end.max(pkt_num);
+                self.ranges.remove(&prev_start);
+            }
+        }
+        if let Some((&next_start, &next_end)) = self.ranges.range(pkt_num..).next() {
+            if next_start <= end + 1 {
+                end = end.max(next_end);
+                self.ranges.remove(&next_start);
+            }
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// Whether `pkt_num` has been recorded.
+    fn contains(&self, pkt_num: u64) -> bool {
+        self.ranges
+            .range(..=pkt_num)
+            .next_back()
+            .map(|(_, &end)| end >= pkt_num)
+            .unwrap_or(false)
+    }
+
+    /// Smallest packet number at or after `first_sent` that hasn't been recorded.
+    fn smallest_unreplied_gap(&self, first_sent: u64) -> Option<u64> {
+        let mut candidate = first_sent;
+        loop {
+            match self.ranges.range(..=candidate).next_back() {
+                Some((&start, &end)) if start <= candidate && candidate <= end => {
+                    candidate = end + 1;
+                }
+                _ => return Some(candidate),
+            }
+        }
+    }
+
+    /// The `[start, end]` gaps (inclusive) within `[first_sent, last_sent]` that haven't been
+    /// recorded, in ascending order.
+    fn missing_ranges(&self, first_sent: u64, last_sent: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut cursor = first_sent;
+        while cursor <= last_sent {
+            match self.ranges.range(..=cursor).next_back() {
+                Some((&start, &end)) if start <= cursor && cursor <= end => {
+                    cursor = end + 1;
+                }
+                _ => {
+                    let gap_end = self
+                        .ranges
+                        .range(cursor..)
+                        .next()
+                        .map(|(&start, _)| start - 1)
+                        .unwrap_or(last_sent)
+                        .min(last_sent);
+                    gaps.push((cursor, gap_end));
+                    cursor = gap_end + 1;
+                }
+            }
+        }
+        gaps
+    }
+}
+
+/// Outcome of `Flowlet::add_reply`, so `check_reply` can tell a new reply apart from one that
+/// doesn't change the flowlet's state instead of just logging and moving on.
+#[derive(Debug, PartialEq, Eq)]
+enum ReplyPlacement {
+    /// The peer packet number hadn't been seen before and was recorded.
+    Novel,
+    /// A reply for this peer packet number was already recorded.
+    Duplicate,
+    /// The flowlet was already completed; the reply arrived too late to be placed.
+    TooOld,
+}
+
 /// Each flowlet corresponds to a sub-queue of packets.
 /// This struct manages the flowlet metadata and packet nums.
 struct Flowlet {
@@ -34,8 +258,12 @@ struct Flowlet {
 
     /// Reply packet numbers for the packets in the flowlet.
     reply_pkt_times: Vec<time::Instant>,
-    /// The packet numbers of the reply packets.
+    /// The packet numbers of the reply packets, in arrival order (needed alongside
+    /// `reply_pkt_times` for DP matching, which reasons about arrival order, not identity).
     reply_pkt_nums: Vec<u64>,
+    /// Coalescing index over `reply_pkt_nums`, for an O(log n) check of whether a given peer
+    /// packet number has already come back as a reply (`add_reply` uses it to drop duplicates).
+    replied_nums: RangeTracker,
 
     /// The time when the first packet of the flowlet comes.
     begin_time: time::Instant,
@@ -45,6 +273,11 @@ struct Flowlet {
 
     /// If the flowlet is complete.
     complete: bool,
+
+    /// Packet numbers already declared lost by `detect_incremental_loss` and pushed to
+    /// `PacketQueue::detected_loss`, so the completion-time DP matcher doesn't retransmit
+    /// them a second time.
+    incrementally_lost: BTreeSet<u64>,
 }
 
 impl Flowlet {
@@ -55,9 +288,11 @@ impl Flowlet {
             close_count: 0,
             reply_pkt_times: Vec::new(),
             reply_pkt_nums: Vec::new(),
+            replied_nums: RangeTracker::new(),
             begin_time,
             end_time: begin_time,
             complete: false,
+            incrementally_lost: BTreeSet::new(),
         }
     }
 
@@ -83,6 +318,29 @@ impl Flowlet {
         self.complete = true;
     }
 
+    /// Add a reply packet number to the flowlet, returning whether it was new, a duplicate of
+    /// an already-recorded reply, or arrived too late for an already-completed flowlet.
+    fn add_reply(&mut self, come_time: time::Instant, pkt_num: u64) -> ReplyPlacement {
+        if self.complete {
+            debug!(
+                "reply for peer pkt {} arrived after flowlet was completed, ignoring",
+                pkt_num
+            );
+            return ReplyPlacement::TooOld;
+        }
+        if self.replied_nums.contains(pkt_num) {
+            debug!("duplicate reply for peer pkt {}, ignoring", pkt_num);
+            return ReplyPlacement::Duplicate;
+        }
+        self.replied_nums.insert(pkt_num);
+        self.reply_pkt_times.push(come_time);
+        self.reply_pkt_nums.push(pkt_num);
+        if self.reply_pkt_times.len() > self.pkt_nums.len() {
+            debug!("reply num > data num");
+        }
+        ReplyPlacement::Novel
+    }
+
     /// Check if the flowlet is complete.
     fn is_complete(&self) -> bool {
         self.complete
@@ -93,15 +351,6 @@ impl Flowlet {
         self.reply_pkt_times.len() == self.pkt_nums.len()
     }
 
-    /// Add a reply.
-    fn add_reply(&mut self, come_time: time::Instant, pkt_num: u64) {
-        self.reply_pkt_times.push(come_time);
-        self.reply_pkt_nums.push(pkt_num);
-        if self.reply_pkt_times.len() > self.pkt_nums.len() {
-            debug!("reply num > data num");
-        }
-    }
-
     /// Establish mapping between sent and reply packets when only part of packets are replied.
     /// Output: sent_to_reply_map. A indexs list(len=len of sent_pkts): sent_to_reply_map[i] is the sent_pkts[i] matched reply_pkts index
     /// This function uses a DP algorithm to find the best mapping.
@@ -151,9 +400,18 @@ impl Flowlet {
 
     // Match sent packets to reply packets using a DP algorithm.
     // The DP algorithm minimizes ∑ |(reply[j] - sent[i]) - rtt|,
-    // while preserving temporal order (monotonic matching).
-    // Returns the index mapping from sent to reply; unmatched entries are set to usize::MAX.
+    // while preserving temporal order (monotonic matching). Both sides can go unmatched: a
+    // sent packet with no reply (loss) is free, since that's the expected, common case this
+    // is built to detect. A reply with no sent packet left to match it (e.g. a duplicate, or
+    // a reply routed here from another flowlet by `check_reply`'s closest-flowlet search) is
+    // the unusual case, so it costs `UNMATCHED_REPLY_PENALTY` - `reply.len() > sent.len()` is
+    // valid input, not just `<=`, but every real match is still preferred over dropping a
+    // reply when one is available.
     fn match_sent_reply_dp(&self, sent: Vec<f64>, reply: Vec<f64>, used_rtt: f64) -> Vec<usize> {
+        // Comfortably larger than any realistic |timestamp delta - rtt| in seconds, so the DP
+        // only drops a reply when there's truly no sent packet left to match it against.
+        const UNMATCHED_REPLY_PENALTY: f64 = 1e9;
+
         let n = sent.len();
         let m = reply.len();
 
@@ -170,11 +428,14 @@ impl Flowlet {
             // skip sent[i-1]
             prev[i][0] = Some((i - 1, 0, -1));
         }
+        for j in 1..=m {
+            dp[0][j] = dp[0][j - 1] + UNMATCHED_REPLY_PENALTY;
+            // skip reply[j-1]: no sent packet is left to match it against.
+            prev[0][j] = Some((0, j - 1, -1));
+        }
 
         for i in 1..=n {
-            // the already matched reply num should not > sent num
-            let upto = std::cmp::min(i, m);
-            for j in 1..=upto {
+            for j in 1..=m {
                 // option A: match sent[i-1] with reply[j-1]
                 let cost = ((reply[j - 1] - sent[i - 1]) - used_rtt).abs();
 
@@ -187,6 +448,15 @@ impl Flowlet {
                     best_prev = (i - 1, j, -1);
                 }
 
+                // option C: skip reply[j-1], so a flowlet with more replies than sent
+                // packets (e.g. a reply misrouted here from another flowlet) doesn't leave
+                // the backtrack below with no valid path to (n, m)
+                let skip_reply = dp[i][j - 1] + UNMATCHED_REPLY_PENALTY;
+                if skip_reply < best {
+                    best = skip_reply;
+                    best_prev = (i, j - 1, -1);
+                }
+
                 dp[i][j] = best;
                 prev[i][j] = Some(best_prev);
             }
@@ -314,6 +584,80 @@ impl Flowlet {
         }
         rtt_samples
     }
+
+    /// Incrementally detect loss within this still-open flowlet, complementing the DP-based
+    /// `match_sent_part_reply`/`extract_part_loss` that only run once the whole flowlet is
+    /// complete. Meant to be called every time a reply is added to the flowlet.
+    ///
+    /// Runs the same DP matcher against the replies seen so far to find the largest-numbered
+    /// sent packet with a matched reply (the "reply edge"), then declares any earlier,
+    /// still-unmatched sent packet lost once it has either fallen `pkt_threshold` (normally
+    /// `RTT_PACKET_THRESHOLD`, grown by `PacketQueue::check_spurious_loss` if this path is prone
+    /// to reordering) packets behind the edge, or `RTT_TIME_THRESHOLD * max(smoothed_rtt,
+    /// latest_rtt)` behind in time (RFC 9002-style kPacketThreshold/kTimeThreshold), subject to
+    /// the same close-packet/eliciting-threshold exceptions `extract_part_loss` applies at
+    /// completion. Returns only newly-declared losses; a packet is never returned twice.
+    fn detect_incremental_loss(
+        &mut self,
+        sent_pkt_times: &Vec<time::Instant>,
+        now: time::Instant,
+        smoothed_rtt: time::Duration,
+        latest_rtt: time::Duration,
+        eliciting_threshold: u8,
+        pkt_threshold: usize,
+    ) -> BTreeSet<u64> {
+        if self.reply_pkt_times.is_empty() {
+            return BTreeSet::new();
+        }
+
+        let base = sent_pkt_times[0]; // only used in this function for time to f64 conversion
+        let sent: Vec<f64> = sent_pkt_times
+            .iter()
+            .map(|t| t.duration_since(base).as_secs_f64())
+            .collect();
+        let reply: Vec<f64> = self
+            .reply_pkt_times
+            .iter()
+            .map(|t| t.duration_since(base).as_secs_f64())
+            .collect();
+        let used_rtt = smoothed_rtt.max(latest_rtt);
+        let map = self.match_sent_reply_dp(sent, reply, used_rtt.as_secs_f64());
+
+        // the reply edge: the largest-numbered sent packet with a matched reply
+        let edge_idx = match map.iter().rposition(|&r| r != usize::MAX) {
+            Some(idx) => idx,
+            None => return BTreeSet::new(), // no sent packet matched yet
+        };
+
+        let mut candidates = BTreeSet::new();
+        for i in 0..edge_idx {
+            if map[i] != usize::MAX {
+                continue; // already has a matched reply
+            }
+            let pkt_num = self.pkt_nums[i];
+            if self.incrementally_lost.contains(&pkt_num) {
+                continue; // already declared lost by an earlier call
+            }
+            let pkt_gap = edge_idx - i;
+            let elapsed = now.duration_since(sent_pkt_times[i]);
+            if pkt_gap >= pkt_threshold || elapsed > used_rtt.mul_f64(RTT_TIME_THRESHOLD) {
+                candidates.insert(pkt_num);
+            }
+        }
+        if candidates.is_empty() {
+            return BTreeSet::new();
+        }
+
+        // apply the same close-packet/eliciting-threshold exceptions as extract_part_loss:
+        // only keep candidates it would also consider unreplied.
+        let consistent = self.extract_part_loss(sent_pkt_times, &map, eliciting_threshold);
+        let new_lost: BTreeSet<u64> = candidates
+            .into_iter()
+            .filter(|pkt_num| consistent.contains(pkt_num))
+            .collect();
+        self.incrementally_lost.extend(&new_lost);
+        new_lost
+    }
 }
 
 impl std::fmt::Debug for Flowlet {
@@ -350,6 +694,13 @@ pub struct RawUdpPacket {
     /// The first packet is 1.
     number: u64,
 
+    /// Identifies which original UDP datagram this packet was read out of. `PacketQueue::add`
+    /// is called once per received datagram, so today this is always equal to `number`; once a
+    /// caller starts splitting a coalesced datagram (see `quic_parse::Header::parse_coalesced`)
+    /// into more than one queued `RawUdpPacket`, the split packets would share one `datagram_id`
+    /// so retransmission can put them back on the wire together.
+    datagram_id: u64,
+
     /// Timestamp of this packet.
     timestamp: time::Instant,
 
@@ -367,14 +718,20 @@ impl RawUdpPacket {
     pub fn pkt_num(&self) -> u64 {
         self.number
     }
+
+    /// Return the id of the original UDP datagram this packet belongs to.
+    pub fn datagram_id(&self) -> u64 {
+        self.datagram_id
+    }
 }
 
 impl std::fmt::Debug for RawUdpPacket {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "UdpPacket {{ number: {}, timestamp: {:?}, payload len: {} }}",
+            "UdpPacket {{ number: {}, datagram_id: {}, timestamp: {:?}, payload len: {} }}",
             self.number,
+            self.datagram_id,
             self.timestamp,
             self.payload.len()
         )
@@ -424,9 +781,44 @@ impl Packet {
     }
 }
 
+/// A structured record of one of `PacketQueue`'s key state transitions, for offline RTT/loss
+/// analysis. Covers what the free-text `debug!`/`trace!`/`info!` calls throughout this module
+/// already log, in a form a caller can post-process instead of scraping log lines.
+#[derive(Debug)]
+pub enum QueueEvent {
+    /// A new flowlet began with its first packet number.
+    FlowletCreated { pkt_num: u64 },
+    /// A reply was matched to `flowlet_idx` (0 = the queue's current front flowlet).
+    ReplyMatched { flowlet_idx: usize, pkt_num: u64 },
+    /// The front flowlet was completed and removed, with the RTT samples its matched replies
+    /// yielded (empty if every packet was lost, or none were replied).
+    FlowletCompleted { rtt_samples: Vec<time::Duration> },
+    /// A packet was declared lost (incrementally, at flowlet completion, or via a PTO probe)
+    /// and queued for retransmission. `packet_id` is the same hex fingerprint `packet_id`
+    /// prints to the debug log.
+    PacketLost { pkt_num: u64, packet_id: String },
+    /// `on_timeout` found the front flowlet past its deadline: either a PTO backoff (`pto_count`
+    /// counts the consecutive backoffs so far) or, once `MAX_PTO_COUNT` is reached, giving up on
+    /// it.
+    TimeoutFired { pto_count: u32 },
+}
+
+/// Where `PacketQueue` sends its `QueueEvent`s: a file, a channel, or (the default) nowhere.
+/// A trait object so `PacketQueue` doesn't need to depend on whichever one a caller picks.
+pub trait QueueEventSink: Send {
+    fn emit(&mut self, ts: time::Duration, event: QueueEvent);
+}
+
+/// The default sink, installed by `PacketQueue::new`: drops every event, so emitting them costs
+/// nothing until a caller opts in via `set_event_sink`.
+struct NullEventSink;
+
+impl QueueEventSink for NullEventSink {
+    fn emit(&mut self, _ts: time::Duration, _event: QueueEvent) {}
+}
+
 /// A queue of packets.
 /// Each connection has two queues: one for the packets-from-client and one for the packets-from-server.
-#[derive(Debug)]
 pub struct PacketQueue {
     /// The packets in the queue.
     packets: VecDeque<Packet>,
@@ -434,6 +826,11 @@ pub struct PacketQueue {
     /// To be retranmitted packets.
     detected_loss: VecDeque<RawUdpPacket>,
 
+    /// Bytes of packets pushed to `detected_loss` since the last `take_lossed_bytes` call.
+    /// Drained by `Conn` to feed the congestion controller's `on_loss`, separately from
+    /// `detected_loss` itself, which is drained independently for retransmission.
+    lossed_bytes_pending: usize,
+
     /// Flowlets of the connection.
     flowlets: VecDeque<Flowlet>,
 
@@ -456,6 +853,76 @@ pub struct PacketQueue {
     /// The factors of the flowlet timeout.
     flowlet_interval_factor: f64,
     pub flowlet_end_factor: f64,
+
+    /// RTT estimate fed by the RTT samples extracted from completed flowlets, used in place of
+    /// a caller-supplied `reply_rtt` for matching and timeout decisions.
+    rtt: RttEstimate,
+
+    /// Consecutive probe-timeout backoffs applied to the front flowlet since its last reply
+    /// progress (see `pto_timeout`). Reset to 0 on a reply to the front flowlet, or once it's
+    /// finally given up on and removed.
+    pto_count: u32,
+
+    /// Packet number and original sent time of each packet declared lost (incrementally or at
+    /// flowlet completion) within `RECENTLY_LOST_WINDOW`, oldest first. `check_reply` consults
+    /// this to recognize a reply that arrives late for an already-retransmitted packet, so the
+    /// retransmission can be recognized as spurious instead of silently accepted.
+    recently_lost: VecDeque<(u64, time::Instant)>,
+
+    /// Packet-reordering threshold `detect_incremental_loss` uses in place of the fixed
+    /// `RTT_PACKET_THRESHOLD`, grown by `check_spurious_loss` (capped at
+    /// `RTT_PACKET_THRESHOLD + MAX_PKT_REORDER_GROWTH`) so a path's natural reordering stops
+    /// producing phantom losses.
+    pkt_reorder_threshold: usize,
+
+    /// Total packets declared lost (incrementally or at flowlet completion).
+    total_lost: u64,
+
+    /// Of `total_lost`, how many later turned out to have a reply arrive anyway (see
+    /// `recently_lost`).
+    spurious_lost: u64,
+
+    /// Replies dropped by `Flowlet::add_reply` because the same peer packet number had already
+    /// been recorded (see `ReplyPlacement::Duplicate`).
+    duplicate_replies: u64,
+
+    /// When this queue was created, for timestamping `QueueEvent`s relative to queue start.
+    created: time::Instant,
+
+    /// Where `QueueEvent`s are sent; `NullEventSink` unless a caller opts in via
+    /// `set_event_sink`.
+    event_sink: Box<dyn QueueEventSink>,
+
+    /// Sent time of the earliest packet in the current contiguous run of declared-lost packets
+    /// with no intervening reply. `None` when there's no loss streak in progress (see
+    /// `extend_loss_streak`/`reset_loss_streak`).
+    loss_streak_start: Option<time::Instant>,
+
+    /// Sent time of the latest packet in the current loss streak.
+    loss_streak_end: Option<time::Instant>,
+
+    /// Whether the current loss streak's span (`loss_streak_end - loss_streak_start`) has
+    /// reached `persistent_congestion_duration`, signaling a collapsed path rather than a brief
+    /// spike. Cleared as soon as a fresh reply is matched in `check_reply`.
+    persistent_congestion: bool,
+}
+
+impl std::fmt::Debug for PacketQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PacketQueue")
+            .field("packets", &self.packets)
+            .field("detected_loss", &self.detected_loss)
+            .field("flowlets", &self.flowlets)
+            .field("processed", &self.processed)
+            .field("eliciting_threshold", &self.eliciting_threshold)
+            .field("rtt", &self.rtt)
+            .field("pto_count", &self.pto_count)
+            .field("total_lost", &self.total_lost)
+            .field("spurious_lost", &self.spurious_lost)
+            .field("duplicate_replies", &self.duplicate_replies)
+            .field("persistent_congestion", &self.persistent_congestion)
+            .finish_non_exhaustive()
+    }
 }
 
 impl PacketQueue {
@@ -463,6 +930,7 @@ impl PacketQueue {
         PacketQueue {
             packets: VecDeque::new(),
             detected_loss: VecDeque::new(),
+            lossed_bytes_pending: 0,
             flowlets: VecDeque::new(),
             processed: 0,
             reply_nums: 0,
@@ -471,30 +939,126 @@ impl PacketQueue {
             eliciting_threshold: DEFAULT_ELICITING_THRESHOLD,
             flowlet_interval_factor: 2.0,
             flowlet_end_factor: 2.0,
+            rtt: RttEstimate::new(),
+            pto_count: 0,
+            recently_lost: VecDeque::new(),
+            pkt_reorder_threshold: RTT_PACKET_THRESHOLD,
+            total_lost: 0,
+            spurious_lost: 0,
+            duplicate_replies: 0,
+            created: time::Instant::now(),
+            event_sink: Box::new(NullEventSink),
+            loss_streak_start: None,
+            loss_streak_end: None,
+            persistent_congestion: false,
         }
     }
 
+    /// Whether the current run of declared-lost packets indicates a collapsed path (a span of
+    /// sends at least `persistent_congestion_duration` long, all lost, with no intervening
+    /// reply) rather than a brief loss spike. Cleared by the next reply `check_reply` matches.
+    pub fn persistent_congestion(&self) -> bool {
+        self.persistent_congestion
+    }
+
+    /// Install a structured event sink; `PacketQueue::new` starts with a no-op sink so event
+    /// emission costs nothing until a caller opts in.
+    pub fn set_event_sink(&mut self, sink: Box<dyn QueueEventSink>) {
+        self.event_sink = sink;
+    }
+
+    /// Send `event` to the installed event sink, timestamped relative to `self.created`.
+    fn emit_event(&mut self, now: time::Instant, event: QueueEvent) {
+        self.event_sink.emit(now.duration_since(self.created), event);
+    }
+
+    /// Total packets declared lost so far (incrementally or at flowlet completion).
+    pub fn total_lost(&self) -> u64 {
+        self.total_lost
+    }
+
+    /// Of `total_lost`, how many were later recognized as spurious (a reply arrived anyway).
+    pub fn spurious_lost(&self) -> u64 {
+        self.spurious_lost
+    }
+
+    /// Replies dropped because the same peer packet number had already been recorded.
+    pub fn duplicate_replies(&self) -> u64 {
+        self.duplicate_replies
+    }
+
     pub fn set_factors(&mut self, flowlet_interval_factor: f64, flowlet_end_factor: f64) {
         self.flowlet_interval_factor = flowlet_interval_factor;
         self.flowlet_end_factor = flowlet_end_factor;
     }
 
+    /// Seed the RTT estimate with a sample measured outside of flowlet matching (e.g. the
+    /// caller's handshake-timing bootstrap), if no sample has landed yet. Once flowlets start
+    /// completing, `rtt` is kept up to date from `Flowlet::extract_rtt_samples` instead.
+    pub fn seed_rtt(&mut self, sample: time::Duration, now: time::Instant) {
+        if !self.rtt.has_sample() {
+            self.rtt.update(sample, now);
+        }
+    }
+
+    /// The RTT estimate's current smoothed value, `Duration::ZERO` before the first sample.
+    fn current_rtt(&self) -> time::Duration {
+        self.rtt.smoothed_rtt()
+    }
+
     /// Return the timeout of the queue.
     /// The timeout is setted by the first flowlet.
     /// timeout = time to the last packet of flowlet1 + flowlet timeout
     /// Return Some(Duration to timeout). Some(0) means timeout already happened
-    pub fn timeout(&self, now: time::Instant, reply_rtt: time::Duration) -> Option<time::Duration> {
+    pub fn timeout(&self, now: time::Instant) -> Option<time::Duration> {
+        if !self.rtt.has_sample() {
+            // the RTT estimate has not been seeded yet, can't set the timeout
+            return None;
+        }
         // the timeout is setted by the first flowlet: should recv the reply of the end packet
         let fl_timeout = match self.flowlets.front() {
             None => None, //no flowlet in the queue
             Some(fl) => {
-                let flowlet_timeout = self.flowlet_end_timeout(&reply_rtt);
-                Some(fl.end_time + flowlet_timeout - now) // the value will be >= 0, 0 means timeout already happened
+                let flowlet_timeout = self.flowlet_end_timeout().max(self.pto_timeout());
+                let end_deadline = fl.end_time + flowlet_timeout - now; // the value will be >= 0, 0 means timeout already happened
+                Some(match self.next_incremental_loss_deadline(now) {
+                    Some(loss_deadline) => end_deadline.min(loss_deadline),
+                    None => end_deadline,
+                })
             }
         };
         return fl_timeout;
     }
 
+    /// Time remaining until `detect_incremental_loss`'s `RTT_TIME_THRESHOLD` rule would next
+    /// declare a packet in the front flowlet lost, i.e. `now` to the earliest still-unreplied,
+    /// not-yet-incrementally-lost packet's `sent_time + RTT_TIME_THRESHOLD * used_rtt`. Lets
+    /// `timeout` wake the caller up for early loss detection instead of waiting for the full
+    /// flowlet deadline. `None` if there's no front flowlet, or it has no reply yet to anchor
+    /// `detect_incremental_loss`'s edge-packet logic on (mirrors that function's own check).
+    fn next_incremental_loss_deadline(&self, now: time::Instant) -> Option<time::Duration> {
+        let fl = self.flowlets.front()?;
+        if fl.reply_pkt_times.is_empty() {
+            return None;
+        }
+        let used_rtt = self.rtt.smoothed_rtt().max(self.rtt.latest_rtt);
+        let window = used_rtt.mul_f64(RTT_TIME_THRESHOLD);
+        fl.pkt_nums
+            .iter()
+            .filter(|pkt_num| {
+                !fl.replied_nums.contains(**pkt_num) && !fl.incrementally_lost.contains(*pkt_num)
+            })
+            .map(|pkt_num| self.get_packet(*pkt_num).timestamp() + window)
+            .min()
+            .map(|deadline| {
+                if deadline > now {
+                    deadline - now
+                } else {
+                    time::Duration::ZERO
+                }
+            })
+    }
+
     /// Record the packet interval.
     /// The interval is used to determine the new flowlet.
     /// The interval is smoothed by the last value.
@@ -510,16 +1074,45 @@ impl PacketQueue {
         self.smoothed_interval.mul_f64(self.flowlet_interval_factor)
     }
 
-    fn flowlet_end_timeout(&self, reply_rtt: &time::Duration) -> time::Duration {
-        *reply_rtt
-            + self
-                .flowlet_timeout(reply_rtt)
-                .mul_f64(self.flowlet_end_factor)
+    fn flowlet_end_timeout(&self) -> time::Duration {
+        let rtt = self.current_rtt();
+        rtt + self.flowlet_timeout(&rtt).mul_f64(self.flowlet_end_factor)
+    }
+
+    /// RFC 9002-style PTO base (`smoothed_rtt + max(4 * rttvar, granularity)`), shared by
+    /// `pto_timeout` (doubled per backoff) and `persistent_congestion_duration` (tripled flat).
+    fn pto_base(&self) -> time::Duration {
+        self.rtt.smoothed_rtt() + self.rtt.rttvar().mul_f64(4.0).max(PTO_GRANULARITY)
+    }
+
+    /// RFC 9002-style PTO, doubled per `pto_count` consecutive backoff. Anchored to measured RTT
+    /// variance rather than the packet-interval heuristic `flowlet_end_timeout` otherwise uses,
+    /// so a front flowlet stuck under high/varying latency gets a widening deadline instead of
+    /// being given up on at a fixed cutoff. `timeout`/`on_timeout` take the max of the two.
+    fn pto_timeout(&self) -> time::Duration {
+        self.pto_base() * 2u32.pow(self.pto_count.min(MAX_PTO_COUNT))
+    }
+
+    /// RFC 9002-style persistent-congestion duration: the send-time span a contiguous run of
+    /// declared-lost packets (see `extend_loss_streak`) must cover, with no intervening reply,
+    /// before `persistent_congestion` is raised.
+    fn persistent_congestion_duration(&self) -> time::Duration {
+        self.pto_base() * 3
+    }
+
+    /// True if `calibration_rtt_sample` deviates from the RTT estimate enough to warrant
+    /// discarding already-replied flowlets. See `RttEstimate::deviates`.
+    pub fn rtt_deviates(&self, calibration_rtt_sample: time::Duration) -> bool {
+        self.rtt.deviates(calibration_rtt_sample)
     }
 
     // If RTT deviation is detected, reset.
     // Delete all flowlets that have found a reply; only leave flowlets that have no reply yet.
-    pub fn reset_due_to_rtt_deviation(&mut self) {
+    // Return whether a reset happened.
+    pub fn reset_due_to_rtt_deviation(&mut self, calibration_rtt_sample: time::Duration) -> bool {
+        if !self.rtt_deviates(calibration_rtt_sample) {
+            return false;
+        }
         while let Some(front) = self.flowlets.front() {
             if front.reply_pkt_times.is_empty() {
                 break; // stop when encounter a flowlet without reply
@@ -533,6 +1126,7 @@ impl PacketQueue {
                 assert_eq!(pkt.number(), pkt_num);
             }
         }
+        true
     }
 
     // Measure the eliciting threshold.
@@ -616,6 +1210,14 @@ impl PacketQueue {
                 }
             }
         }
+        if new_flowlet {
+            self.emit_event(
+                forward_ts,
+                QueueEvent::FlowletCreated {
+                    pkt_num: self.processed,
+                },
+            );
+        }
 
         // save the packet
         match payload {
@@ -630,6 +1232,7 @@ impl PacketQueue {
             Some(payload) => {
                 self.packets.push_back(Packet::Raw(RawUdpPacket {
                     number: self.processed,
+                    datagram_id: self.processed,
                     timestamp: forward_ts,
                     payload,
                 }));
@@ -641,7 +1244,8 @@ impl PacketQueue {
     /// Complete flowlets[0]. But based on the new algorithms: DP based mapping; considering the eliciting threshold.
     /// Must be called after the flowlet is checked as complete.
     /// Return: rtt samples from the completed flowlet.
-    fn complete_one_flowlet(&mut self, reply_rtt: time::Duration) -> Vec<time::Duration> {
+    /// `now` is used to fold any extracted RTT samples into `self.rtt`.
+    fn complete_one_flowlet(&mut self, now: time::Instant) -> Vec<time::Duration> {
         assert!(self.flowlets[0].is_complete());
         let fl = &self.flowlets[0];
 
@@ -680,7 +1284,7 @@ impl PacketQueue {
                 .iter()
                 .map(|pkt_num| self.get_packet(*pkt_num).timestamp())
                 .collect();
-            let sent_to_reply_map = fl.match_sent_part_reply(&sent_pkt_times, reply_rtt);
+            let sent_to_reply_map = fl.match_sent_part_reply(&sent_pkt_times, self.current_rtt());
             assert_eq!(fl.pkt_nums.len(), sent_to_reply_map.len());
             lossed_pkts = fl.extract_part_loss(
                 &sent_pkt_times,
@@ -690,25 +1294,218 @@ impl PacketQueue {
             rtt_samples = fl.extract_rtt_samples(&sent_to_reply_map, &sent_pkt_times);
         }
 
-        self.remove_a_complete_flowlet(lossed_pkts);
+        for &sample in &rtt_samples {
+            self.rtt.update(sample, now);
+        }
+        self.emit_event(
+            now,
+            QueueEvent::FlowletCompleted {
+                rtt_samples: rtt_samples.clone(),
+            },
+        );
+
+        self.remove_a_complete_flowlet(now, lossed_pkts);
         rtt_samples
     }
 
+    /// Run `Flowlet::detect_incremental_loss` on `flowlets[flowlet_idx]` and push any
+    /// newly-detected losses straight into `detected_loss`, ahead of that flowlet completing.
+    /// The original packet is left in `self.packets` (it's only removed, in order, once its
+    /// flowlet completes); a clone of its payload is what gets queued for retransmission.
+    fn push_incremental_loss(&mut self, flowlet_idx: usize, now: time::Instant) {
+        let sent_pkt_times: Vec<time::Instant> = self.flowlets[flowlet_idx]
+            .pkt_nums
+            .iter()
+            .map(|pkt_num| self.get_packet(*pkt_num).timestamp())
+            .collect();
+        let eliciting_threshold = self.eliciting_threshold;
+        let smoothed_rtt = self.rtt.smoothed_rtt();
+        let latest_rtt = self.rtt.latest_rtt;
+        let pkt_threshold = self.pkt_reorder_threshold;
+        let new_lost = self.flowlets[flowlet_idx].detect_incremental_loss(
+            &sent_pkt_times,
+            now,
+            smoothed_rtt,
+            latest_rtt,
+            eliciting_threshold,
+            pkt_threshold,
+        );
+        for pkt_num in new_lost {
+            match self.get_packet(pkt_num) {
+                Packet::Raw(p) => {
+                    let payload = p.payload().clone();
+                    let timestamp = p.timestamp;
+                    let datagram_id = p.datagram_id;
+                    info!("incrementally detected loss, pkt num: {}", pkt_num);
+                    self.lossed_bytes_pending += payload.len();
+                    self.note_declared_loss(now, pkt_num, timestamp, &payload);
+                    self.detected_loss.push_back(RawUdpPacket {
+                        number: pkt_num,
+                        datagram_id,
+                        timestamp,
+                        payload,
+                    });
+                }
+                Packet::Retrans(_) => {
+                    debug!("retransmit packet need not to be retransmitted again");
+                }
+            }
+        }
+    }
+
+    /// Record a just-declared-lost packet for `check_reply`'s spurious-loss check, bump
+    /// `total_lost`, and emit a `PacketLost` event. See `recently_lost`.
+    fn note_declared_loss(
+        &mut self,
+        now: time::Instant,
+        pkt_num: u64,
+        sent_time: time::Instant,
+        payload: &Vec<u8>,
+    ) {
+        self.total_lost += 1;
+        self.recently_lost.push_back((pkt_num, sent_time));
+        self.extend_loss_streak(sent_time);
+        self.emit_event(
+            now,
+            QueueEvent::PacketLost {
+                pkt_num,
+                packet_id: Self::packet_id(payload),
+            },
+        );
+    }
+
+    /// Fold a just-declared-lost packet's send time into the current loss streak, and raise
+    /// `persistent_congestion` once the streak's span reaches `persistent_congestion_duration`.
+    /// The streak is bounded by two lost packets (its start and end), so a single lost packet
+    /// alone never triggers it.
+    fn extend_loss_streak(&mut self, sent_time: time::Instant) {
+        let start = *self.loss_streak_start.get_or_insert(sent_time);
+        let end = self.loss_streak_end.map_or(sent_time, |e| e.max(sent_time));
+        self.loss_streak_start = Some(start.min(sent_time));
+        self.loss_streak_end = Some(end);
+        if end.duration_since(self.loss_streak_start.unwrap()) >= self.persistent_congestion_duration() {
+            self.persistent_congestion = true;
+        }
+    }
+
+    /// Clear the loss streak and `persistent_congestion`: a fresh reply means the path made
+    /// progress, so any earlier loss run no longer indicates a collapsed path.
+    fn reset_loss_streak(&mut self) {
+        self.loss_streak_start = None;
+        self.loss_streak_end = None;
+        self.persistent_congestion = false;
+    }
+
+    /// Drop `recently_lost` entries older than `RECENTLY_LOST_WINDOW`; they're too stale for a
+    /// late reply to plausibly still be for them.
+    fn prune_recently_lost(&mut self, now: time::Instant) {
+        while let Some(&(_, sent_time)) = self.recently_lost.front() {
+            if now.duration_since(sent_time) > RECENTLY_LOST_WINDOW {
+                self.recently_lost.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Check whether `now`'s reply plausibly belongs to a packet already declared lost (and
+    /// thus already retransmitted) rather than to anything still tracked in `flowlets`: its
+    /// implied send time (`now - reply_rtt`) falls within one `flowlet_timeout` (the same
+    /// packet-grouping granularity flowlet matching itself uses) of the lost packet's real sent
+    /// time. On a match, count it as spurious and grow `pkt_reorder_threshold` so the path's
+    /// reordering stops producing phantom losses, up to `MAX_PKT_REORDER_GROWTH` above
+    /// `RTT_PACKET_THRESHOLD`.
+    fn check_spurious_loss(&mut self, now: time::Instant, reply_rtt: time::Duration) {
+        self.prune_recently_lost(now);
+        let tolerance = self.flowlet_timeout(&reply_rtt);
+        let implied_sent_time = match now.checked_sub(reply_rtt) {
+            Some(t) => t,
+            None => return,
+        };
+        let matched = self
+            .recently_lost
+            .iter()
+            .position(|&(_, sent_time)| {
+                let err = if sent_time > implied_sent_time {
+                    sent_time - implied_sent_time
+                } else {
+                    implied_sent_time - sent_time
+                };
+                err <= tolerance
+            })
+            .map(|idx| self.recently_lost.remove(idx).unwrap());
+        if let Some((pkt_num, _)) = matched {
+            self.spurious_lost += 1;
+            let max_threshold = RTT_PACKET_THRESHOLD + MAX_PKT_REORDER_GROWTH;
+            if self.pkt_reorder_threshold < max_threshold {
+                self.pkt_reorder_threshold += 1;
+            }
+            info!(
+                "spurious loss detected for pkt {}, growing pkt_reorder_threshold to {}",
+                pkt_num, self.pkt_reorder_threshold
+            );
+        }
+    }
+
+    /// QUIC PTO-style probe: on a PTO backoff, push up to `PTO_PROBE_COUNT` of the front
+    /// flowlet's most-recently-sent, still-unreplied packets into `detected_loss` so the
+    /// caller retransmits them to elicit a disambiguating reply, instead of `on_timeout`
+    /// dumping the whole flowlet into `detected_loss` on the first missed deadline. Marks the
+    /// probed packets `incrementally_lost` so `complete_one_flowlet` doesn't retransmit them a
+    /// second time if the flowlet is later abandoned for real.
+    fn emit_pto_probes(&mut self, now: time::Instant) {
+        let fl = &self.flowlets[0];
+        let probes: Vec<u64> = fl
+            .pkt_nums
+            .iter()
+            .rev()
+            .filter(|pkt_num| {
+                !fl.replied_nums.contains(**pkt_num) && !fl.incrementally_lost.contains(*pkt_num)
+            })
+            .take(PTO_PROBE_COUNT)
+            .copied()
+            .collect();
+        if probes.is_empty() {
+            return;
+        }
+        self.flowlets[0].incrementally_lost.extend(&probes);
+        for pkt_num in probes {
+            match self.get_packet(pkt_num) {
+                Packet::Raw(p) => {
+                    let payload = p.payload().clone();
+                    let timestamp = p.timestamp;
+                    let datagram_id = p.datagram_id;
+                    info!("PTO probe retransmission for pkt {}", pkt_num);
+                    self.lossed_bytes_pending += payload.len();
+                    self.note_declared_loss(now, pkt_num, timestamp, &payload);
+                    self.detected_loss.push_back(RawUdpPacket {
+                        number: pkt_num,
+                        datagram_id,
+                        timestamp,
+                        payload,
+                    });
+                }
+                Packet::Retrans(_) => {
+                    debug!("retransmit packet need not to be retransmitted again");
+                }
+            }
+        }
+    }
+
     /// Find reply packet for flowlet.
     /// now is the time when the reply packet comes.
     /// pkt_num is the packet number(number in peer queue, not this queue) of the reply packet.
     /// If return None, means the reply packet is not inserted into the flowlet.
-    pub fn check_reply(
-        &mut self,
-        now: time::Instant,
-        pkt_num: u64,
-        reply_rtt: time::Duration,
-    ) -> Option<Vec<time::Duration>> {
+    pub fn check_reply(&mut self, now: time::Instant, pkt_num: u64) -> Option<Vec<time::Duration>> {
         // 1.< begin_time1 - 1/2 flow_let timeout: error
         // 2.else if only 1 flowlet, push to flowlet1
         // 3.else: find the most suitable flowlet, push to it, and label all the previous flowlets as complete
         self.reply_nums += 1;
-        let flowlet_timeout_addition = self.flowlet_end_timeout(&reply_rtt) - reply_rtt;
+        let reply_rtt = self.current_rtt();
+        if reply_rtt > time::Duration::ZERO {
+            self.check_spurious_loss(now, reply_rtt);
+        }
+        let flowlet_timeout_addition = self.flowlet_end_timeout() - reply_rtt;
         let flowlets_len = self.flowlets.len();
         trace!("check reply, pkt: {}", pkt_num);
         if flowlets_len == 0 {
@@ -727,10 +1524,19 @@ impl PacketQueue {
             return None;
         } else if flowlets_len == 1 {
             // push to flowlet1
-            self.flowlets[0].add_reply(now, pkt_num);
-            // mark the flowlet as complete if there are too many packets
-            if self.flowlets[0].pkt_nums.len() > FLOWLET_MAX_PKTS {
-                self.flowlets[0].set_as_complete(); // avoid protect flowlet longer than FLOWLET_MAX_PKTS
+            match self.flowlets[0].add_reply(now, pkt_num) {
+                ReplyPlacement::Duplicate => self.duplicate_replies += 1,
+                ReplyPlacement::TooOld => {}
+                ReplyPlacement::Novel => {
+                    self.pto_count = 0; // reply progress on the front flowlet: reset PTO backoff
+                    self.reset_loss_streak();
+                    self.emit_event(now, QueueEvent::ReplyMatched { flowlet_idx: 0, pkt_num });
+                    self.push_incremental_loss(0, now);
+                    // mark the flowlet as complete if there are too many packets
+                    if self.flowlets[0].pkt_nums.len() > FLOWLET_MAX_PKTS {
+                        self.flowlets[0].set_as_complete(); // avoid protect flowlet longer than FLOWLET_MAX_PKTS
+                    }
+                }
             }
             // output the flowlet reply operation
             trace!("reply to flowlet1: {:?}", self.flowlets[0]);
@@ -758,35 +1564,61 @@ impl PacketQueue {
                 }
             }
             // push to the replyed flowlet
-            self.flowlets[replyed_flowlet].add_reply(now, pkt_num);
+            match self.flowlets[replyed_flowlet].add_reply(now, pkt_num) {
+                ReplyPlacement::Duplicate => self.duplicate_replies += 1,
+                ReplyPlacement::TooOld => {}
+                ReplyPlacement::Novel => {
+                    if replyed_flowlet == 0 {
+                        self.pto_count = 0; // reply progress on the front flowlet: reset PTO backoff
+                    }
+                    self.reset_loss_streak();
+                    self.emit_event(
+                        now,
+                        QueueEvent::ReplyMatched { flowlet_idx: replyed_flowlet, pkt_num },
+                    );
+                    self.push_incremental_loss(replyed_flowlet, now);
+                }
+            }
             // output the flowlet reply operation
             trace!(
                 "reply to flowlet{}: {:?}",
                 replyed_flowlet + 1,
                 self.flowlets[replyed_flowlet]
             );
-            // label all the previous flowlets as complete
+            // A reply landing past flowlet 0 means all earlier flowlets are being superseded, but
+            // a reply for flowlet i can itself be reordered and arrive after this one for flowlet
+            // i+1 — so run the incremental-loss scan on them, but only complete a flowlet once its
+            // own time/packet thresholds are exceeded, rather than unconditionally, so it's still
+            // eligible to take its own reply in the meantime.
             for i in 0..replyed_flowlet {
-                self.flowlets[i].set_as_complete();
+                self.push_incremental_loss(i, now);
+                let flowlet_end_timeout = self.flowlet_end_timeout();
+                let fl = &self.flowlets[i];
+                let exceeded = fl.pkt_nums.len() > FLOWLET_MAX_PKTS
+                    || now > fl.end_time + flowlet_end_timeout;
+                if exceeded {
+                    self.flowlets[i].set_as_complete();
+                }
             }
         }
 
         // if some flowlets are complete, remove it and return the RTT sample
         let mut rtt_samples = Vec::new();
         while !self.flowlets.is_empty() && self.flowlets[0].is_complete() {
-            rtt_samples.append(&mut self.complete_one_flowlet(reply_rtt));
+            rtt_samples.append(&mut self.complete_one_flowlet(now));
         }
         Some(rtt_samples)
     }
 
-    pub fn on_timeout(
-        &mut self,
-        now: time::Instant,
-        reply_rtt: time::Duration,
-    ) -> Vec<time::Duration> {
+    pub fn on_timeout(&mut self, now: time::Instant) -> Vec<time::Duration> {
         debug!("PacketQueue: check timeout at {:?}", now);
+        // the front flowlet's time-threshold deadline may fire well before its full flowlet
+        // timeout; run the same scan `check_reply` does on a reply so losses are caught here too.
+        if matches!(self.next_incremental_loss_deadline(now), Some(d) if d.is_zero()) {
+            self.push_incremental_loss(0, now);
+        }
         // check if any flowlets are timeout
-        let flowlet_timeout = self.flowlet_end_timeout(&reply_rtt);
+        let flowlet_timeout = self.flowlet_end_timeout();
         let mut rtt_samples = Vec::new();
         // complete the timeout flowlets
         loop {
@@ -797,15 +1629,30 @@ impl PacketQueue {
                 }
                 _ => self.flowlets[0].end_time,
             };
-            if now > end_time1 + flowlet_timeout {
-                // timeout
+            if now > end_time1 + flowlet_timeout.max(self.pto_timeout()) {
+                if self.pto_count < MAX_PTO_COUNT {
+                    // probe-timeout backoff: still within budget, so probe the front flowlet's
+                    // newest packets and give it another, longer round instead of giving up on
+                    // its protection now.
+                    self.pto_count += 1;
+                    debug!(
+                        "PacketQueue: front flowlet reply stalled, PTO backoff to pto_count {}",
+                        self.pto_count
+                    );
+                    self.emit_event(now, QueueEvent::TimeoutFired { pto_count: self.pto_count });
+                    self.emit_pto_probes(now);
+                    break;
+                }
+                // backoff budget exhausted: give up on this flowlet
+                self.emit_event(now, QueueEvent::TimeoutFired { pto_count: self.pto_count });
                 self.flowlets[0].set_as_complete();
+                self.pto_count = 0;
             } else {
                 break;
             }
 
             // check if there is any packet loss
-            rtt_samples.append(&mut self.complete_one_flowlet(reply_rtt));
+            rtt_samples.append(&mut self.complete_one_flowlet(now));
         }
         rtt_samples
     }
@@ -842,7 +1689,7 @@ impl PacketQueue {
     /// Remove the complete flowlet.
     /// Calling this function will remove the complete flowlet from the queue.
     /// This must be called after the flowlet is unuseful: after the loss check, RTT measurement.
-    fn remove_a_complete_flowlet(&mut self, lossed_pkts: BTreeSet<u64>) {
+    fn remove_a_complete_flowlet(&mut self, now: time::Instant, lossed_pkts: BTreeSet<u64>) {
         assert!(self.flowlets[0].is_complete());
         // Remove the completed flowlet1 and its pkts from the queue.
         debug!("remove flowlet: {:?}", self.flowlets[0]);
@@ -871,9 +1718,15 @@ impl PacketQueue {
             // if the packet is lossed, push it to the detected loss queue
             let pkt = self.packets.pop_front().unwrap();
             assert_eq!(pkt.number(), pkt_num);
-            if lossed_pkts.contains(&pkt_num) {
+            if fl.incrementally_lost.contains(&pkt_num) {
+                // already pushed to detected_loss by detect_incremental_loss; drop it here so
+                // it isn't retransmitted a second time
+                debug!("pkt {} already retransmitted incrementally", pkt_num);
+            } else if lossed_pkts.contains(&pkt_num) {
                 match pkt {
                     Packet::Raw(pkt) => {
+                        self.lossed_bytes_pending += pkt.payload().len();
+                        self.note_declared_loss(now, pkt.number, pkt.timestamp, pkt.payload());
                         self.detected_loss.push_back(pkt);
                     }
                     Packet::Retrans(_) => {
@@ -900,6 +1753,12 @@ impl PacketQueue {
         !self.detected_loss.is_empty()
     }
 
+    /// Bytes of newly-detected-lost packets since the last call, for feeding the congestion
+    /// controller's `on_loss`. Resets the pending total.
+    pub fn take_lossed_bytes(&mut self) -> usize {
+        std::mem::take(&mut self.lossed_bytes_pending)
+    }
+
     /// Return the timestamp of the oldest packet.
     pub fn oldest_ts(&self) -> Option<time::Instant> {
         self.packets.front().map(|p| p.timestamp())
@@ -1002,11 +1861,14 @@ mod tests {
         let pkt1_payload = hex::decode("f00000000114fb6d58d157197c287b00").unwrap();
         let pkt2_payload = hex::decode("f00000000114fb6d58d157197c287b00").unwrap();
 
+        // seed the RTT estimate the same way the caller's handshake-timing bootstrap would
+        pq.seed_rtt(rtt, start_time);
+
         pq.add(pkt1_time, Some(pkt1_payload), rtt, true);
         assert_eq!(pq.flowlets.len(), 1);
         assert_eq!(pq.flowlets[0].pkt_nums, vec![1]);
 
-        pq.check_reply(reply1_time, 1, rtt);
+        pq.check_reply(reply1_time, 1);
         assert_eq!(pq.flowlets.len(), 1);
         assert_eq!(pq.flowlets[0].reply_pkt_times.len(), 1);
         assert_eq!(pq.flowlets[0].is_complete(), false);
@@ -1015,7 +1877,7 @@ mod tests {
         assert_eq!(pq.flowlets.len(), 2);
         assert_eq!(pq.flowlets[1].pkt_nums, vec![2]);
 
-        pq.check_reply(reply2_time, 2, rtt);
+        pq.check_reply(reply2_time, 2);
     }
 
     #[test]
@@ -1060,4 +1922,110 @@ mod tests {
         let map4 = run(&sent4, &reply4, rtt4);
         assert_eq!(map4, expected4, "DP Example 4 failed");
     }
+
+    #[test]
+    fn test_rtt_estimate() {
+        let mut rtt = RttEstimate::new();
+        assert_eq!(rtt.has_sample(), false);
+
+        let now = time::Instant::now();
+        rtt.update(Duration::from_millis(100), now);
+        // first sample: smoothed_rtt = latest_rtt, rttvar = latest_rtt / 2
+        assert_eq!(rtt.smoothed_rtt, Duration::from_millis(100));
+        assert_eq!(rtt.rttvar, Duration::from_millis(50));
+        assert_eq!(rtt.min_rtt, Duration::from_millis(100));
+
+        rtt.update(Duration::from_millis(150), now);
+        // rttvar = 3/4*50 + 1/4*|100-150| = 50ms; smoothed_rtt = 7/8*100 + 1/8*150 = 106.25ms
+        assert_eq!(rtt.rttvar, Duration::from_millis(50));
+        assert_eq!(rtt.smoothed_rtt, Duration::from_micros(106_250));
+        assert_eq!(rtt.min_rtt, Duration::from_millis(100));
+
+        rtt.update(Duration::from_millis(50), now);
+        assert_eq!(rtt.min_rtt, Duration::from_millis(50));
+
+        assert_eq!(rtt.deviates(Duration::from_secs(1)), true);
+        assert_eq!(rtt.deviates(Duration::from_millis(100)), false);
+    }
+
+    #[test]
+    fn test_detect_incremental_loss() {
+        let t0 = time::Instant::now();
+        let mut fl = Flowlet::new(1, t0);
+        fl.add(2, t0 + Duration::from_millis(1));
+        fl.add(3, t0 + Duration::from_millis(2));
+        let sent_pkt_times = vec![
+            t0,
+            t0 + Duration::from_millis(1),
+            t0 + Duration::from_millis(2),
+        ];
+
+        // reply only to pkt 3 (10ms RTT), well past the 1,2 have been forwarded.
+        let now = t0 + Duration::from_millis(12);
+        fl.add_reply(now, 3);
+
+        // pkt 1 is 12ms behind the reply edge, past 9/8 * 10ms: lost.
+        // pkt 2 is only 11ms behind and just 1 packet before the edge, short of the 3-packet
+        // threshold: not yet lost.
+        let lost = fl.detect_incremental_loss(
+            &sent_pkt_times,
+            now,
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            1,
+            RTT_PACKET_THRESHOLD,
+        );
+        assert_eq!(lost, BTreeSet::from([1]));
+        assert!(fl.incrementally_lost.contains(&1));
+
+        // calling again with no new replies must not re-report the same packet.
+        let lost_again = fl.detect_incremental_loss(
+            &sent_pkt_times,
+            now,
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+            1,
+            RTT_PACKET_THRESHOLD,
+        );
+        assert!(lost_again.is_empty());
+    }
+
+    #[test]
+    fn test_range_tracker() {
+        let mut t = RangeTracker::new();
+        assert_eq!(t.contains(5), false);
+        assert_eq!(t.smallest_unreplied_gap(1), Some(1));
+
+        t.insert(5);
+        assert!(t.contains(5));
+        assert_eq!(t.smallest_unreplied_gap(1), Some(1));
+        assert_eq!(t.smallest_unreplied_gap(5), Some(6));
+
+        // duplicate insert is a no-op
+        t.insert(5);
+        assert_eq!(t.ranges.len(), 1);
+
+        // bordering insert merges into the existing run
+        t.insert(6);
+        assert_eq!(t.ranges, std::collections::BTreeMap::from([(5, 6)]));
+
+        // disjoint insert starts a new run
+        t.insert(1);
+        assert_eq!(
+            t.ranges,
+            std::collections::BTreeMap::from([(1, 1), (5, 6)])
+        );
+
+        // insert that bridges two runs merges them into one
+        t.insert(2);
+        t.insert(3);
+        t.insert(4);
+        assert_eq!(t.ranges, std::collections::BTreeMap::from([(1, 6)]));
+
+        assert_eq!(t.missing_ranges(1, 10), vec![(7, 10)]);
+        assert_eq!(t.smallest_unreplied_gap(1), Some(7));
+
+        t.insert(9);
+        assert_eq!(t.missing_ranges(1, 10), vec![(7, 8), (10, 10)]);
+    }
 }
@@ -47,6 +47,28 @@ impl Task {
     pub fn pop_front(&mut self) -> Option<RawUdpPacket> {
         self.retrans_queue.pop_front()
     }
+
+    /// Peek the next packet without removing it, e.g. to check its size against a
+    /// congestion window before committing to send it.
+    pub fn front(&self) -> Option<&RawUdpPacket> {
+        self.retrans_queue.front()
+    }
+
+    /// Pop every queued packet that shares the front packet's `datagram_id`, i.e. the full set
+    /// of coalesced QUIC packets that were originally sent in one UDP datagram. Use this instead
+    /// of repeated `pop_front` calls when retransmitting, so a coalesced Initial+Handshake
+    /// datagram is re-emitted as one datagram rather than re-chunked arbitrarily across sends.
+    pub fn pop_datagram(&mut self) -> Option<Vec<RawUdpPacket>> {
+        let datagram_id = self.retrans_queue.front()?.datagram_id();
+        let mut datagram = Vec::new();
+        while let Some(p) = self.retrans_queue.front() {
+            if p.datagram_id() != datagram_id {
+                break;
+            }
+            datagram.push(self.retrans_queue.pop_front().unwrap());
+        }
+        Some(datagram)
+    }
 }
 
 impl std::fmt::Display for Task {
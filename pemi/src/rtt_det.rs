@@ -3,19 +3,52 @@ use log::debug;
 use pnet::packet::icmp::echo_reply::EchoReplyPacket;
 use pnet::packet::icmp::echo_request::{IcmpCodes, MutableEchoRequestPacket};
 use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
-use pnet::packet::{util, MutablePacket, Packet};
+use pnet::packet::icmpv6::echo_reply::EchoReplyPacket as Echov6ReplyPacket;
+use pnet::packet::icmpv6::echo_request::{
+    Icmpv6Codes as EchoV6Codes, MutableEchoRequestPacket as MutableEchoV6RequestPacket,
+};
+use pnet::packet::icmpv6::{Icmpv6Packet, Icmpv6Types};
+use pnet::packet::{util, Packet};
+use pnet_base::core_net::Ipv6Addr as PnetIpv6Addr;
 use socket2::{Domain, Protocol, Socket, Type};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use tokio::net::UdpSocket;
 
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::time::Instant;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
 
-/// Record requests for a single IP address.
+/// Outcome of draining `RttDetector`'s pending-send queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStatus {
+    /// At least one probe is still queued; wait for the socket to report writable again before
+    /// calling `flush_pending` again.
+    Ongoing,
+    /// Every queued probe has been handed to the kernel.
+    Complete,
+}
+
+/// Floor under the RTTVAR term of `IpRequests::rto`, analogous to RFC 6298's clock-granularity
+/// bound for systems with a coarse timer resolution.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// RFC 6298's initial RTO, used by `IpRequests::rto` before a destination has produced its
+/// first RTT sample.
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// How many multiples of `IpRequests::rto` an unanswered request is kept before
+/// `IpRequests::evict_stale` drops it as lost.
+const STALE_REQUEST_RTO_MULTIPLE: u32 = 4;
+
+/// Record requests for a single IP address, plus a Jacobson/Karels (RFC 6298) smoothed RTT
+/// estimate fed by each reply this address sends back.
 struct IpRequests {
     seq: u16,
     requests: HashMap<u16, Instant>,
+    /// Smoothed RTT (SRTT). `None` until the first sample arrives.
+    srtt: Option<Duration>,
+    /// EWMA of the mean deviation of samples from `srtt`.
+    rttvar: Duration,
 }
 
 impl IpRequests {
@@ -23,6 +56,8 @@ impl IpRequests {
         IpRequests {
             seq: 1,
             requests: HashMap::new(),
+            srtt: None,
+            rttvar: Duration::ZERO,
         }
     }
     fn send_request(&mut self) {
@@ -35,17 +70,67 @@ impl IpRequests {
     fn seq(&self) -> u16 {
         self.seq
     }
+
+    /// Folds a new RTT sample into `srtt`/`rttvar` per RFC 6298 section 2: on the first sample
+    /// R, `SRTT = R` and `RTTVAR = R/2`; on each later sample R', `RTTVAR` moves a quarter of
+    /// the way toward `|SRTT - R'|` and `SRTT` moves an eighth of the way toward `R'`.
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(sample);
+                self.rttvar = self.rttvar.mul_f64(0.75) + deviation.mul_f64(0.25);
+                self.srtt = Some(srtt.mul_f64(0.875) + sample.mul_f64(0.125));
+            }
+        }
+    }
+
+    /// Current smoothed RTT, or `None` before the first sample.
+    fn smoothed_rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// RFC 6298 retransmission timeout: `SRTT + max(CLOCK_GRANULARITY, 4*RTTVAR)`, falling back
+    /// to `INITIAL_RTO` before the first sample has landed.
+    fn rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => srtt + CLOCK_GRANULARITY.max(self.rttvar * 4),
+            None => INITIAL_RTO,
+        }
+    }
+
+    /// Drops requests that have gone unanswered for longer than `STALE_REQUEST_RTO_MULTIPLE *
+    /// rto()`, treating them as lost so unanswered entries don't accumulate forever.
+    fn evict_stale(&mut self, now: Instant) {
+        let deadline = self.rto() * STALE_REQUEST_RTO_MULTIPLE;
+        self.requests
+            .retain(|_, &mut sent_at| now.duration_since(sent_at) <= deadline);
+    }
 }
 
 /// A struct to detect RTT (Round Trip Time) using ICMP Echo Requests.
+///
+/// Keeps one socket pair per IP family: destinations are dispatched to the ICMPv4 or ICMPv6
+/// path based on `SocketAddr::ip()`, so PEMI can calibrate RTT against both kinds of peer on a
+/// dual-stack deployment.
 pub struct RttDetector {
-    socket: socket2::Socket,
-    tokio_socket: UdpSocket, // for async operations, point to the same socket as `socket`
-    id: u16,                 // ICMP identifier
-    send_buf: Vec<u8>,
-    recv_buf: Vec<u8>,
+    socket_v4: socket2::Socket,
+    tokio_socket_v4: UdpSocket, // for async operations, points to the same socket as `socket_v4`
+    socket_v6: socket2::Socket,
+    tokio_socket_v6: UdpSocket, // for async operations, points to the same socket as `socket_v6`
+    id: u16,                    // ICMP identifier
+    send_buf_v4: Vec<u8>,
+    recv_buf_v4: Vec<u8>,
+    send_buf_v6: Vec<u8>,
+    recv_buf_v6: Vec<u8>,
     sent_requests: HashMap<IpAddr, IpRequests>,
-    // TODO: del long time unreplied requests
+
+    /// Probes that couldn't be sent immediately because the socket reported `EWOULDBLOCK`,
+    /// queued (destination, already-checksummed packet bytes) for `flush_pending` to retry.
+    pending_sends: VecDeque<(SocketAddr, Vec<u8>)>,
 
     // For info print with ts (only used in debug, not needed in production)
     begin_time: Instant,
@@ -53,32 +138,32 @@ pub struct RttDetector {
 
 impl RttDetector {
     pub fn new() -> Self {
-        // create the socket for ICMPv4
-        let socket = Self::init_icmp_socket();
-        let tokio_socket = unsafe {
-            // Duplicate the underlying raw fd so that the original `socket`
-            // and the `std::net::UdpSocket`/Tokio wrapper each own separate
-            // file descriptors. This prevents a double-close when both are
-            // dropped (which causes an IO safety abort on recent Rust).
-            let fd = socket.as_raw_fd();
-            let fd_dup = libc::dup(fd);
-            if fd_dup == -1 {
-                panic!(
-                    "failed to duplicate fd: {}",
-                    std::io::Error::last_os_error()
-                );
-            }
-            let std_socket = std::net::UdpSocket::from_raw_fd(fd_dup);
-            UdpSocket::from_std(std_socket)
-                .expect("Failed to convert std socket to Tokio UdpSocket")
-        };
+        let socket_v4 = Self::init_icmp_socket(
+            Domain::IPV4,
+            Protocol::ICMPV4,
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        );
+        let tokio_socket_v4 = Self::duplicate_as_tokio_socket(&socket_v4);
+
+        let socket_v6 = Self::init_icmp_socket(
+            Domain::IPV6,
+            Protocol::ICMPV6,
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        );
+        let tokio_socket_v6 = Self::duplicate_as_tokio_socket(&socket_v6);
+
         RttDetector {
-            socket,
-            tokio_socket,
+            socket_v4,
+            tokio_socket_v4,
+            socket_v6,
+            tokio_socket_v6,
             id: 2025,
-            send_buf: vec![0; 64], // Use 64 bytes for ICMP echo request
-            recv_buf: vec![0; 64], // Use 64 bytes for ICMP echo reply
+            send_buf_v4: vec![0; 64], // Use 64 bytes for ICMP echo request
+            recv_buf_v4: vec![0; 64], // Use 64 bytes for ICMP echo reply
+            send_buf_v6: vec![0; 64],
+            recv_buf_v6: vec![0; 64],
             sent_requests: HashMap::new(),
+            pending_sends: VecDeque::new(),
             begin_time: Instant::now(),
         }
     }
@@ -93,27 +178,66 @@ impl RttDetector {
         now.duration_since(self.begin_time)
     }
 
-    /// Create a new ICMPv4 socket and bind it to an unspecified address.
-    /// The socket is transformed into a Tokio UdpSocket for asynchronous operations.
-    fn init_icmp_socket() -> socket2::Socket {
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))
-            .expect("Failed to create ICMPv4 socket");
-        let src = std::net::SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    /// Create a new ICMP socket of the given family and bind it to an unspecified address.
+    fn init_icmp_socket(domain: Domain, protocol: Protocol, bind_addr: IpAddr) -> socket2::Socket {
+        let socket = Socket::new(domain, Type::DGRAM, Some(protocol))
+            .unwrap_or_else(|e| panic!("Failed to create ICMP socket ({domain:?}): {e}"));
+        let src = std::net::SocketAddr::new(bind_addr, 0);
         socket
             .bind(&src.into())
-            .expect("Failed to bind ICMPv4 socket");
+            .unwrap_or_else(|e| panic!("Failed to bind ICMP socket ({domain:?}): {e}"));
         socket
             .set_ttl(64)
-            .expect("Failed to set TTL for ICMPv4 socket");
-        socket
-            .set_nonblocking(true)
-            .expect("Failed to set ICMPv4 socket to non-blocking mode");
+            .unwrap_or_else(|e| panic!("Failed to set TTL for ICMP socket ({domain:?}): {e}"));
+        socket.set_nonblocking(true).unwrap_or_else(|e| {
+            panic!("Failed to set ICMP socket ({domain:?}) to non-blocking mode: {e}")
+        });
         socket
     }
 
+    /// Duplicates `socket`'s underlying fd into a Tokio `UdpSocket` so the two owners each hold
+    /// a separate fd (avoids a double-close-on-drop IO safety abort on recent Rust).
+    fn duplicate_as_tokio_socket(socket: &socket2::Socket) -> UdpSocket {
+        unsafe {
+            let fd = socket.as_raw_fd();
+            let fd_dup = libc::dup(fd);
+            if fd_dup == -1 {
+                panic!(
+                    "failed to duplicate fd: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            let std_socket = std::net::UdpSocket::from_raw_fd(fd_dup);
+            UdpSocket::from_std(std_socket)
+                .expect("Failed to convert std socket to Tokio UdpSocket")
+        }
+    }
+
+    /// Discovers the local address the kernel would pick to reach `dest`, by connecting a
+    /// throwaway UDP socket (which only resolves routing, no packets are sent). Needed for the
+    /// ICMPv6 pseudo-header checksum (RFC 4443 section 2.3), which ICMPv4 does not require.
+    fn local_addr_for(dest: Ipv6Addr) -> Ipv6Addr {
+        let probe = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+            .expect("Failed to bind probe UDP socket for source address discovery");
+        probe
+            .connect((dest, 0))
+            .expect("Failed to connect probe UDP socket for source address discovery");
+        match probe
+            .local_addr()
+            .expect("Failed to get local address from probe socket")
+            .ip()
+        {
+            IpAddr::V6(addr) => addr,
+            IpAddr::V4(_) => unreachable!("IPv6 probe socket returned an IPv4 address"),
+        }
+    }
+
     // TODO: count the overhead of the ICMP recv and recv(only count one for every RTT)
     /// Send an ICMP request to the specified destination address.
-    /// In PEMI implementation, every send_to call must be successful(We now haven't process the resend logic for write failure).
+    ///
+    /// The packet is queued and an immediate drain of `pending_sends` is attempted; if the
+    /// nonblocking socket reports `EWOULDBLOCK` the packet stays queued instead of being lost
+    /// or aborting the connection, and `flush_pending` retries it once the socket is writable.
     pub fn send_request(&mut self, dest: std::net::SocketAddr) {
         let ip_requests = match self.sent_requests.get_mut(&dest.ip()) {
             Some(requests) => requests,
@@ -123,39 +247,101 @@ impl RttDetector {
                 self.sent_requests.get_mut(&dest.ip()).unwrap()
             }
         };
+        ip_requests.evict_stale(Instant::now());
+
+        let packet = match dest.ip() {
+            IpAddr::V4(_) => {
+                let mut icmp = MutableEchoRequestPacket::new(&mut self.send_buf_v4[..])
+                    .expect("Failed to create ICMPv4 Echo Request packet");
+                icmp.set_icmp_type(IcmpTypes::EchoRequest);
+                icmp.set_icmp_code(IcmpCodes::NoCode);
+                icmp.set_identifier(self.id);
+                icmp.set_sequence_number(ip_requests.seq());
+                icmp.set_checksum(util::checksum(icmp.packet(), 1));
+                icmp.packet().to_vec()
+            }
+            IpAddr::V6(dest_v6) => {
+                let src_v6 = Self::local_addr_for(dest_v6);
 
-        let mut icmp = MutableEchoRequestPacket::new(&mut self.send_buf[..])
-            .expect("Failed to create ICMP Echo Request packet");
-        icmp.set_icmp_type(IcmpTypes::EchoRequest);
-        icmp.set_icmp_code(IcmpCodes::NoCode);
-        icmp.set_identifier(self.id);
-        icmp.set_sequence_number(ip_requests.seq());
-        icmp.set_checksum(util::checksum(icmp.packet(), 1));
+                let mut icmp = MutableEchoV6RequestPacket::new(&mut self.send_buf_v6[..])
+                    .expect("Failed to create ICMPv6 Echo Request packet");
+                icmp.set_icmpv6_type(Icmpv6Types::EchoRequest);
+                icmp.set_icmpv6_code(EchoV6Codes::NoCode);
+                icmp.set_identifier(self.id);
+                icmp.set_sequence_number(ip_requests.seq());
+                let checksum = pnet::packet::icmpv6::checksum(
+                    &Icmpv6Packet::new(icmp.packet()).expect("Failed to reparse ICMPv6 packet"),
+                    &PnetIpv6Addr::from(src_v6.octets()),
+                    &PnetIpv6Addr::from(dest_v6.octets()),
+                );
+                icmp.set_checksum(checksum);
+                icmp.packet().to_vec()
+            }
+        };
 
         ip_requests.send_request();
-        self.socket
-            .send_to(icmp.packet_mut(), &dest.into())
-            .expect("Failed to send ICMP request"); // if write fails, panic with the error message
+        self.pending_sends.push_back((dest, packet));
+        self.flush_pending();
     }
 
     pub async fn wait_readable(&self) -> Result<(), std::io::Error> {
-        // Wait for the socket to become readable
-        self.tokio_socket.readable().await?;
-        Ok(())
+        // Wait for either socket to become readable.
+        tokio::select! {
+            res = self.tokio_socket_v4.readable() => res,
+            res = self.tokio_socket_v6.readable() => res,
+        }
+    }
+
+    /// Waits for either ICMP socket to report writable, so a caller holding a
+    /// `WriteStatus::Ongoing` from `flush_pending` knows when to retry it.
+    pub async fn wait_writable(&self) -> Result<(), std::io::Error> {
+        tokio::select! {
+            res = self.tokio_socket_v4.writable() => res,
+            res = self.tokio_socket_v6.writable() => res,
+        }
+    }
+
+    /// Attempts to send every queued probe in `pending_sends`, stopping at the first one the
+    /// nonblocking socket isn't ready to accept. Call this after `wait_writable` resolves to
+    /// retry probes that backed up under send pressure.
+    pub fn flush_pending(&mut self) -> WriteStatus {
+        loop {
+            let Some((dest, packet)) = self.pending_sends.front() else {
+                return WriteStatus::Complete;
+            };
+            let dest = *dest;
+            let socket = match dest.ip() {
+                IpAddr::V4(_) => &self.socket_v4,
+                IpAddr::V6(_) => &self.socket_v6,
+            };
+            match socket.send_to(packet, &dest.into()) {
+                Ok(_) => {
+                    self.pending_sends.pop_front();
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return WriteStatus::Ongoing,
+                Err(e) => panic!("Failed to send ICMP request to {dest}: {e}"),
+            }
+        }
+    }
+
+    /// Call after the socket becomes ready to read.
+    /// Returns the IP address that replied alongside the RTT sample, so the caller can
+    /// calibrate only the connections going to that receiver (PEMI may front more than one).
+    pub fn recv_response(&mut self) -> Result<(IpAddr, std::time::Duration), std::io::Error> {
+        match self.recv_response_v4() {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => self.recv_response_v6(),
+            result => result,
+        }
     }
 
-    /// Call after the socket becomes ready to read
-    pub fn recv_response(&mut self) -> Result<std::time::Duration, std::io::Error> {
-        // Here you would implement the logic to receive the ICMP response
-        // and calculate the RTT based on the sent request time.
-        // handle recv
+    fn recv_response_v4(&mut self) -> Result<(IpAddr, std::time::Duration), std::io::Error> {
         let mut mem_buf = unsafe {
-            &mut *(self.recv_buf.as_mut_slice() as *mut [u8] as *mut [std::mem::MaybeUninit<u8>])
+            &mut *(self.recv_buf_v4.as_mut_slice() as *mut [u8] as *mut [std::mem::MaybeUninit<u8>])
         };
-        let (size, server_addr) = self.socket.recv_from(&mut mem_buf)?;
+        let (size, server_addr) = self.socket_v4.recv_from(&mut mem_buf)?;
         let server_addr = server_addr.as_socket().unwrap();
 
-        let reply_packet = IcmpPacket::new(&self.recv_buf[..size]).ok_or_else(|| {
+        let reply_packet = IcmpPacket::new(&self.recv_buf_v4[..size]).ok_or_else(|| {
             std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Failed to parse ICMP packet",
@@ -163,7 +349,7 @@ impl RttDetector {
         })?;
         match reply_packet.get_icmp_type() {
             IcmpTypes::EchoReply => {
-                let reply = EchoReplyPacket::new(&self.recv_buf).ok_or_else(|| {
+                let reply = EchoReplyPacket::new(&self.recv_buf_v4).ok_or_else(|| {
                     std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
                         "Failed to parse ICMP reply",
@@ -183,6 +369,8 @@ impl RttDetector {
                 let now = Instant::now();
                 let duration = now
                     .duration_since(ip_requests.recv_response(seq).expect("No response for seq"));
+                ip_requests.update_rtt(duration);
+                ip_requests.evict_stale(now);
                 let duration_ms = duration.as_micros() as f64 / 1000.0; // Convert to milliseconds
 
                 debug!(
@@ -192,17 +380,84 @@ impl RttDetector {
                     self.elapsed(now),
                     seq,
                     reply.get_identifier(),
-                    self.socket.ttl()?,
+                    self.socket_v4.ttl()?,
                     duration_ms
                 );
-                Ok(duration)
+                Ok((server_addr.ip(), duration))
             }
-            other_type => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Received ICMP packet of type: {:?}", other_type),
-                ));
+            other_type => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Received ICMP packet of type: {:?}", other_type),
+            )),
+        }
+    }
+
+    fn recv_response_v6(&mut self) -> Result<(IpAddr, std::time::Duration), std::io::Error> {
+        let mut mem_buf = unsafe {
+            &mut *(self.recv_buf_v6.as_mut_slice() as *mut [u8] as *mut [std::mem::MaybeUninit<u8>])
+        };
+        let (size, server_addr) = self.socket_v6.recv_from(&mut mem_buf)?;
+        let server_addr = server_addr.as_socket().unwrap();
+
+        let reply_packet = Icmpv6Packet::new(&self.recv_buf_v6[..size]).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Failed to parse ICMPv6 packet",
+            )
+        })?;
+        match reply_packet.get_icmpv6_type() {
+            Icmpv6Types::EchoReply => {
+                let reply = Echov6ReplyPacket::new(&self.recv_buf_v6).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Failed to parse ICMPv6 reply",
+                    )
+                })?;
+                let seq: u16 = reply.get_sequence_number();
+                let ip_requests = self
+                    .sent_requests
+                    .get_mut(&server_addr.ip())
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "No requests found for addr: {:?}:{:?}",
+                            server_addr.ip(),
+                            server_addr.port()
+                        )
+                    });
+                let now = Instant::now();
+                let duration = now
+                    .duration_since(ip_requests.recv_response(seq).expect("No response for seq"));
+                ip_requests.update_rtt(duration);
+                ip_requests.evict_stale(now);
+                let duration_ms = duration.as_micros() as f64 / 1000.0; // Convert to milliseconds
+
+                debug!(
+                    "{}B from {} {:?} icmp6_seq={} id={} time={:.2}ms",
+                    size,
+                    server_addr.ip(),
+                    self.elapsed(now),
+                    seq,
+                    reply.get_identifier(),
+                    duration_ms
+                );
+                Ok((server_addr.ip(), duration))
             }
+            other_type => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Received ICMPv6 packet of type: {:?}", other_type),
+            )),
         }
     }
+
+    /// Current smoothed RTT estimate for `ip`, or `None` if no request has ever been sent to it
+    /// or no reply has landed yet.
+    pub fn smoothed_rtt(&self, ip: IpAddr) -> Option<Duration> {
+        self.sent_requests.get(&ip).and_then(IpRequests::smoothed_rtt)
+    }
+
+    /// Current retransmission timeout for `ip` (RFC 6298), or `None` if no request has ever
+    /// been sent to it.
+    pub fn rto(&self, ip: IpAddr) -> Option<Duration> {
+        self.sent_requests.get(&ip).map(IpRequests::rto)
+    }
 }
@@ -0,0 +1,45 @@
+/* Zero-copy struct views over `octets::Octets` buffers. */
+
+use std::mem;
+
+use crate::common::{Error, ErrorKind};
+
+/// Adds a zero-copy, fixed-layout struct view to `octets::Octets`.
+///
+/// `octets` is a third-party crate, not vendored in this repository, so `peek_struct`
+/// can't be added as one of its own inherent methods; this extension trait gets the
+/// same call syntax (`b.peek_struct::<T>()`) instead, which is the usual way to add
+/// methods to a foreign type in Rust.
+///
+/// This has no caller yet: today's header parsing in `quic_parse` reads QUIC's
+/// variable-width, self-describing fields (varints, length-prefixed connection IDs)
+/// one at a time, which isn't the fixed-layout shape this helper targets. It's meant
+/// for a future fixed-layout struct (e.g. a decrypted short-header packet number
+/// plus a fixed-size AEAD tag) where copying each field out individually would add
+/// needless per-field bounds checks on a hot receive path.
+pub trait StructView<'a> {
+    /// Borrows `size_of::<T>()` bytes at the cursor's current position and
+    /// reinterprets them as a `&T`, without copying or advancing the cursor.
+    ///
+    /// Returns `ErrorKind::Truncated` if fewer than `size_of::<T>()` bytes remain, or
+    /// `ErrorKind::InvalidPacket` if the borrowed bytes aren't validly aligned for `T`
+    /// (`octets::Octets`'s backing buffer carries no alignment guarantee of its own).
+    /// `T: Pod` (bytemuck's "plain old data" bound: no padding, no invalid bit
+    /// patterns, valid for any byte content) is what makes reinterpreting
+    /// attacker-controlled bytes as `&T` sound in the first place.
+    fn peek_struct<T: bytemuck::Pod>(&self) -> Result<&'a T, Error>;
+}
+
+impl<'a> StructView<'a> for octets::Octets<'a> {
+    fn peek_struct<T: bytemuck::Pod>(&self) -> Result<&'a T, Error> {
+        let offset = self.off();
+        let needed = mem::size_of::<T>();
+        let bytes = self.slice(needed).map_err(|_| {
+            Error::from(ErrorKind::Truncated {
+                offset,
+                needed: needed.saturating_sub(self.cap()),
+            })
+        })?;
+        bytemuck::try_from_bytes(bytes).map_err(|_| Error::from(ErrorKind::InvalidPacket))
+    }
+}